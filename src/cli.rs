@@ -0,0 +1,865 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json;
+use rustc_serialize::Encodable;
+
+use crate::devices;
+use crate::drivers;
+use crate::gpsbabel;
+use crate::gpx;
+use crate::library;
+use crate::logsettings::{self, WhenFull};
+use crate::stats;
+use crate::template;
+use crate::verify;
+use crate::Format;
+
+// Exit codes, from sysexits.h
+const EXIT_OK: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_IOERR: i32 = 74;
+const EXIT_TEMPFAIL: i32 = 75;
+const EXIT_PROTOCOL: i32 = 76;
+const EXIT_NOPERM: i32 = 77;
+const EXIT_CANCELLED: i32 = 130;
+
+const USAGE: &str = "Usage:
+  gpsami                      Start the application
+  gpsami list-devices         List the supported devices
+  gpsami list-ports --model MODEL
+                              List the ports for the device model
+  gpsami detect               List the known devices connected
+  gpsami history              List the past downloads
+  gpsami download --model MODEL --port PORT [--format FORMAT]
+                              [--filter FILTERS]
+                              (-o FILE | --output-dir DIR [--template TEMPLATE]
+                              [--timezone TIMEZONE]) [--erase [--no-verify]]
+                              [--shutoff]
+                              Download the data from the device, into FILE
+                              or into DIR named from TEMPLATE. With --erase,
+                              the device is erased once the data is saved,
+                              and checked unless --no-verify is passed. A
+                              device that can only be erased while
+                              downloading can only be downloaded as GPX
+                              without filters. With --shutoff,
+                              the device is turned off after. FORMAT is gpx
+                              (the default), kml, geojson, tcx, csv, nmea,
+                              ozi or fit, or several separated by commas.
+                              FILTERS are gpsbabel filters separated by
+                              commas: simplify=POINTS, simplify=METERSm,
+                              shift=SECONDS, dedupe, merge or hdop=MAX
+  gpsami gpsbabel             Show the gpsbabel found and its version
+  gpsami erase --model MODEL --port PORT
+                              Erase the data on the device
+  gpsami logging --model MODEL --port PORT [--enable | --disable]
+                              Start or stop logging on the device, or show
+                              whether it is logging
+  gpsami shutoff --model MODEL --port PORT
+                              Turn the device off
+  gpsami log-settings --model MODEL --port PORT [--interval SECONDS]
+                              [--distance METERS] [--speed KMH]
+                              [--fields FIELDS] [--when-full overwrite|stop]
+                              Show the logging settings of the device, after
+                              changing those passed. A point is logged every
+                              SECONDS or METERS, above KMH. 0 disables the
+                              criterion. FIELDS are the fields recorded,
+                              separated by commas, like utc,latitude,longitude
+
+The commands accept --gpsbabel PATH to use another gpsbabel,
+--timeout SECONDS to limit how long gpsbabel can download, erase or
+send another command, and --stall-timeout SECONDS to limit how long a
+download or a command can go without any progress. 0 is no limit.
+
+Output is JSON on stdout, errors are JSON on stderr.";
+
+/// The commands.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    ListDevices,
+    ListPorts,
+    Detect,
+    History,
+    Download,
+    Erase,
+    Logging,
+    Shutoff,
+    LogSettings,
+    GpsBabel,
+}
+
+/// Parsed command line options.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    model: Option<String>,
+    port: Option<String>,
+    format: Option<String>,
+    /// The gpsbabel filters, separated by commas.
+    filter: Option<String>,
+    output: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    /// The file name template in `output_dir`.
+    template: Option<String>,
+    timezone: Option<String>,
+    /// The gpsbabel to use.
+    gpsbabel: Option<PathBuf>,
+    /// The timeout of the operation, in seconds.
+    timeout: Option<u64>,
+    /// The timeout without any data, in seconds.
+    stall_timeout: Option<u64>,
+    erase: bool,
+    /// Erase while downloading, without checking the data.
+    no_verify: bool,
+    /// Start or stop logging.
+    logging: Option<bool>,
+    /// Turn the device off after the download.
+    shutoff: bool,
+    /// The logging settings to change.
+    time_interval: Option<f64>,
+    distance_interval: Option<f64>,
+    speed_threshold: Option<f64>,
+    fields: Option<Vec<String>>,
+    when_full: Option<WhenFull>,
+}
+
+/// Result of a download.
+#[derive(RustcEncodable)]
+struct DownloadResult {
+    /// The file of the first format.
+    output: PathBuf,
+    format: String,
+    /// The files of all the formats.
+    outputs: Vec<OutputResult>,
+    erased: bool,
+    /// The device was turned off.
+    shut_off: bool,
+    /// The statistics, for GPX.
+    summary: Option<stats::Stats>,
+}
+
+/// Result of the logging command.
+#[derive(RustcEncodable)]
+struct LoggingResult {
+    logging: bool,
+}
+
+/// The logging settings.
+#[derive(RustcEncodable)]
+struct LogSettingsResult {
+    time_interval: f64,
+    distance_interval: f64,
+    speed_threshold: f64,
+    fields: Vec<String>,
+    when_full: String,
+}
+
+/// A file written.
+#[derive(RustcEncodable)]
+struct OutputResult {
+    output: PathBuf,
+    format: String,
+}
+
+/// gpsbabel as output.
+#[derive(RustcEncodable)]
+struct GpsBabelResult {
+    path: PathBuf,
+    version: String,
+    /// The number of formats it supports.
+    formats: usize,
+}
+
+/// Error as output.
+#[derive(RustcEncodable)]
+struct ErrorResult {
+    error: String,
+    code: i32,
+}
+
+/// Warning as output.
+#[derive(RustcEncodable)]
+struct WarningResult {
+    warning: String,
+}
+
+/// Map the driver error to an exit code.
+fn exit_code(err: &drivers::Error) -> i32 {
+    match *err {
+        drivers::Error::WrongArg => EXIT_USAGE,
+        drivers::Error::NoDriver | drivers::Error::Unsupported | drivers::Error::MissingTool(_) => {
+            EXIT_UNAVAILABLE
+        }
+        drivers::Error::PortBusy(_) | drivers::Error::PermissionDenied(_) => EXIT_NOPERM,
+        drivers::Error::Cancelled => EXIT_CANCELLED,
+        drivers::Error::IOError(_) => EXIT_IOERR,
+        drivers::Error::Timeout(_) | drivers::Error::NotResponding => EXIT_TEMPFAIL,
+        drivers::Error::Protocol(_) => EXIT_PROTOCOL,
+        drivers::Error::Failed(_) => EXIT_FAILED,
+    }
+}
+
+/// Parse the command name.
+fn parse_command(name: &str) -> Option<Command> {
+    match name {
+        "help" | "--help" | "-h" => Some(Command::Help),
+        "list-devices" => Some(Command::ListDevices),
+        "list-ports" => Some(Command::ListPorts),
+        "detect" => Some(Command::Detect),
+        "history" => Some(Command::History),
+        "download" => Some(Command::Download),
+        "erase" => Some(Command::Erase),
+        "logging" => Some(Command::Logging),
+        "shutoff" => Some(Command::Shutoff),
+        "log-settings" => Some(Command::LogSettings),
+        "gpsbabel" => Some(Command::GpsBabel),
+        _ => None,
+    }
+}
+
+/// Parse the options following the command.
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // Allow --option=value
+        let (name, inline_value) = match arg.find('=') {
+            Some(idx) if arg.starts_with("--") => (&arg[..idx], Some(arg[idx + 1..].to_string())),
+            _ => (arg.as_str(), None),
+        };
+        if name == "--erase" {
+            options.erase = true;
+            continue;
+        }
+        if name == "--no-verify" {
+            options.no_verify = true;
+            continue;
+        }
+        if name == "--shutoff" {
+            options.shutoff = true;
+            continue;
+        }
+        if name == "--enable" || name == "--disable" {
+            options.logging = Some(name == "--enable");
+            continue;
+        }
+        let value = match inline_value.or_else(|| iter.next().cloned()) {
+            Some(value) => value,
+            None => return Err(format!("Missing value for {}", name)),
+        };
+        match name {
+            "--model" | "-m" => options.model = Some(value),
+            "--port" | "-p" => options.port = Some(value),
+            "--format" | "-f" => options.format = Some(value),
+            "--filter" | "-x" => options.filter = Some(value),
+            "--output" | "-o" => options.output = Some(PathBuf::from(value)),
+            "--output-dir" | "-d" => options.output_dir = Some(PathBuf::from(value)),
+            "--template" | "-t" => options.template = Some(value),
+            "--timezone" => options.timezone = Some(value),
+            "--gpsbabel" => options.gpsbabel = Some(PathBuf::from(value)),
+            "--interval" => options.time_interval = Some(logsettings::parse_amount(&value)?),
+            "--distance" => options.distance_interval = Some(logsettings::parse_amount(&value)?),
+            "--speed" => options.speed_threshold = Some(logsettings::parse_amount(&value)?),
+            "--fields" => options.fields = Some(logsettings::parse_fields(&value)?),
+            "--when-full" => {
+                options.when_full = Some(
+                    WhenFull::from_id(&value)
+                        .ok_or_else(|| format!("Invalid value for {}", name))?,
+                )
+            }
+            "--timeout" | "--stall-timeout" => {
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid value for {}", name))?;
+                if name == "--timeout" {
+                    options.timeout = Some(secs);
+                } else {
+                    options.stall_timeout = Some(secs);
+                }
+            }
+            _ => return Err(format!("Unknown option {}", name)),
+        }
+    }
+    Ok(options)
+}
+
+/// Print `value` as JSON on stdout.
+fn print_json<T: Encodable>(value: &T) {
+    println!("{}", json::as_pretty_json(value));
+}
+
+/// Print the warning as JSON on stderr.
+fn report_warning(message: &str) {
+    let warning = WarningResult {
+        warning: message.to_string(),
+    };
+    eprintln!("{}", json::as_pretty_json(&warning));
+}
+
+/// Print the error as JSON on stderr and return the exit code.
+fn report_error(message: &str, code: i32) -> i32 {
+    let result = ErrorResult {
+        error: message.to_string(),
+        code,
+    };
+    eprintln!("{}", json::as_pretty_json(&result));
+    code
+}
+
+/// Report the driver error. Return the exit code.
+fn report_driver_error(err: &drivers::Error) -> i32 {
+    report_error(&err.to_string(), exit_code(err))
+}
+
+/// Print the progress on stderr.
+fn print_progress(progress: drivers::Progress) {
+    match progress.fraction() {
+        Some(fraction) => eprint!("\r{} {:.0}%", progress.phase.label(), fraction * 100.0),
+        None => eprint!("\r{}", progress.phase.label()),
+    }
+    let _ = std::io::stderr().flush();
+}
+
+/// Load the devices, the errors of the databases reported as warnings.
+fn load_devices() -> devices::Manager {
+    let manager = devices::Manager::new();
+    for error in manager.db_errors() {
+        report_warning(&error.to_string());
+    }
+    manager
+}
+
+/// Get the device driver for the model and port in options, opened,
+/// and the capability of the model.
+fn open_device(
+    options: &Options,
+) -> Result<(Arc<dyn drivers::Driver + Send + Sync>, devices::Capability), drivers::Error> {
+    let (model, port) = match (&options.model, &options.port) {
+        (Some(model), Some(port)) => (model, port),
+        _ => return Err(drivers::Error::WrongArg),
+    };
+    let mut manager = load_devices();
+    manager.set_model(model);
+    manager.set_port(port);
+    let mut device = manager.get_device().ok_or(drivers::Error::NoDriver)?;
+    let capability = manager
+        .device_capability(model)
+        .ok_or(drivers::Error::NoDriver)?;
+    Arc::get_mut(&mut device).unwrap().open()?;
+    Ok((device, capability))
+}
+
+fn list_devices() -> i32 {
+    let manager = load_devices();
+    print_json(manager.devices_desc());
+    EXIT_OK
+}
+
+fn list_ports(options: &Options) -> i32 {
+    let model = match options.model {
+        Some(ref model) => model,
+        None => return report_error("Missing --model", EXIT_USAGE),
+    };
+    let manager = load_devices();
+    if manager.device_capability(model).is_none() {
+        return report_driver_error(&drivers::Error::NoDriver);
+    }
+    let ports = manager.get_ports_for_model(model).unwrap_or_default();
+    print_json(&ports);
+    EXIT_OK
+}
+
+fn detect() -> i32 {
+    let manager = load_devices();
+    print_json(&manager.detect());
+    EXIT_OK
+}
+
+fn history() -> i32 {
+    match library::Library::load(&library::Library::default_path()) {
+        Ok(library) => {
+            print_json(&library.entries());
+            EXIT_OK
+        }
+        Err(err) => report_error(&err.to_string(), EXIT_IOERR),
+    }
+}
+
+/// Record the download of `output` in the library.
+fn add_to_library(options: &Options, format: Format, output: &Path) {
+    let model = options.model.as_deref().unwrap_or_default();
+    let port = options.port.as_deref().unwrap_or_default();
+    let result =
+        library::Library::load(&library::Library::default_path()).and_then(|mut library| {
+            library.add(library::Entry::from_file(model, port, format, output)?)
+        });
+    if let Err(err) = result {
+        report_warning(&format!("Can't add the download to the library: {}", err));
+    }
+}
+
+/// Compute the statistics of the GPX file `output`.
+fn summarize(output: &Path) -> Option<stats::Stats> {
+    let content = match std::fs::read_to_string(output) {
+        Ok(content) => content,
+        Err(err) => {
+            report_warning(&format!("Can't read {}: {}", output.display(), err));
+            return None;
+        }
+    };
+    match gpx::read(&content) {
+        Ok(document) => Some(stats::Stats::compute(&document)),
+        Err(err) => {
+            report_warning(&format!("Invalid GPX {}: {}", output.display(), err));
+            None
+        }
+    }
+}
+
+/// Parse the comma separated list of formats.
+fn parse_formats(names: &str) -> Result<Vec<Format>, String> {
+    names
+        .split(',')
+        .map(|name| Format::from_id(name.trim()).ok_or_else(|| format!("Unknown format {}", name)))
+        .collect()
+}
+
+fn download(options: &Options) -> i32 {
+    let formats = match parse_formats(options.format.as_deref().unwrap_or("gpx")) {
+        Ok(formats) => formats,
+        Err(message) => return report_error(&message, EXIT_USAGE),
+    };
+    let filters =
+        match gpsbabel::Filter::parse_list(options.filter.as_deref().unwrap_or_default(), ',') {
+            Ok(filters) => filters,
+            Err(message) => return report_error(&message, EXIT_USAGE),
+        };
+    if options.output.is_some() == options.output_dir.is_some() {
+        return report_error("One of --output or --output-dir is needed", EXIT_USAGE);
+    }
+    let (device, capability) = match open_device(options) {
+        Ok(opened) => opened,
+        Err(err) => return report_driver_error(&err),
+    };
+    let converted = !filters.is_empty() || formats.iter().any(|format| *format != Format::Gpx);
+    let erase = verify::erase_mode(&capability, options.erase, !options.no_verify, converted);
+    let erase = match erase {
+        Ok(erase) => erase,
+        Err(err) => return report_driver_error(&err),
+    };
+    let cancel = drivers::CancelToken::new();
+    let result = drivers::download_formats(
+        &*device,
+        &formats,
+        &filters,
+        erase == verify::Erase::WhileDownloading,
+        &print_progress,
+        &cancel,
+    );
+    eprintln!();
+    let temp_outputs = match result {
+        Ok(temp_outputs) => temp_outputs,
+        Err(err) => return report_driver_error(&err),
+    };
+
+    let template = template::Template::new(
+        options
+            .template
+            .as_deref()
+            .unwrap_or(template::DEFAULT_TEMPLATE),
+        template::timezone(options.timezone.as_deref()),
+    );
+    let model = options.model.as_deref().unwrap_or_default();
+    let mut saved = vec![];
+    for (index, (format, temp_output)) in temp_outputs.into_iter().enumerate() {
+        let result = match (&options.output, &options.output_dir) {
+            // The other formats are next to the first one.
+            (Some(output), _) => {
+                let output = if index == 0 {
+                    output.clone()
+                } else {
+                    output.with_extension(template::extension(format))
+                };
+                temp_output
+                    .persist(&output)
+                    .map(|_| output)
+                    .map_err(drivers::Error::IOError)
+            }
+            (None, Some(dir)) => template::save_in(temp_output, format, dir, &template, model),
+            (None, None) => unreachable!(),
+        };
+        match result {
+            Ok(output) => {
+                add_to_library(options, format, &output);
+                saved.push((format, output));
+            }
+            Err(err) => return report_driver_error(&err),
+        }
+    }
+    if let verify::Erase::AfterSaving { check } = erase {
+        let result = verify::erase_after_saving(&*device, &saved, check, &print_progress, &cancel);
+        eprintln!();
+        if let Err(err) = result {
+            return report_driver_error(&err);
+        }
+    }
+    // The files are saved, a failure is only a warning.
+    let shut_off = options.shutoff
+        && match drivers::shutoff_after_download(&*device, &print_progress, &cancel) {
+            Ok(()) => {
+                eprintln!();
+                true
+            }
+            Err(err) => {
+                eprintln!();
+                report_warning(&err.to_string());
+                false
+            }
+        };
+    let summary = saved
+        .iter()
+        .find(|(format, _)| *format == Format::Gpx)
+        .and_then(|(_, output)| summarize(output));
+    if let Some(ref summary) = summary {
+        if summary.is_empty() {
+            report_warning("The download is empty");
+        }
+    }
+    let (format, output) = saved[0].clone();
+    print_json(&DownloadResult {
+        output,
+        format: format.id().to_string(),
+        outputs: saved
+            .into_iter()
+            .map(|(format, output)| OutputResult {
+                output,
+                format: format.id().to_string(),
+            })
+            .collect(),
+        erased: options.erase,
+        shut_off,
+        summary,
+    });
+    EXIT_OK
+}
+
+fn erase(options: &Options) -> i32 {
+    let device = match open_device(options) {
+        Ok((device, _)) => device,
+        Err(err) => return report_driver_error(&err),
+    };
+    let cancel = drivers::CancelToken::new();
+    let result = device.erase(&print_progress, &cancel);
+    eprintln!();
+    match result {
+        Ok(_) => EXIT_OK,
+        Err(err) => report_driver_error(&err),
+    }
+}
+
+fn logging(options: &Options) -> i32 {
+    let device = match open_device(options) {
+        Ok((device, _)) => device,
+        Err(err) => return report_driver_error(&err),
+    };
+    let result = match options.logging {
+        Some(enable) => {
+            let cancel = drivers::CancelToken::new();
+            let result = device.set_logging(enable, &print_progress, &cancel);
+            eprintln!();
+            // Read back what the device did.
+            result.map(|_| device.logging().unwrap_or(enable))
+        }
+        None => device.logging(),
+    };
+    match result {
+        Ok(logging) => {
+            print_json(&LoggingResult { logging });
+            EXIT_OK
+        }
+        Err(err) => report_driver_error(&err),
+    }
+}
+
+fn shutoff(options: &Options) -> i32 {
+    let device = match open_device(options) {
+        Ok((device, _)) => device,
+        Err(err) => return report_driver_error(&err),
+    };
+    let cancel = drivers::CancelToken::new();
+    let result = device.shutoff(&print_progress, &cancel);
+    eprintln!();
+    match result {
+        Ok(_) => EXIT_OK,
+        Err(err) => report_driver_error(&err),
+    }
+}
+
+/// Change the logging settings passed in `options`. Return whether
+/// any was.
+fn change_log_settings(options: &Options, settings: &mut logsettings::LogSettings) -> bool {
+    let before = settings.clone();
+    if let Some(time_interval) = options.time_interval {
+        settings.time_interval = time_interval;
+    }
+    if let Some(distance_interval) = options.distance_interval {
+        settings.distance_interval = distance_interval;
+    }
+    if let Some(speed_threshold) = options.speed_threshold {
+        settings.speed_threshold = speed_threshold;
+    }
+    if let Some(ref fields) = options.fields {
+        settings.fields = fields.clone();
+    }
+    if let Some(when_full) = options.when_full {
+        settings.when_full = when_full;
+    }
+    *settings != before
+}
+
+fn log_settings(options: &Options) -> i32 {
+    let device = match open_device(options) {
+        Ok((device, _)) => device,
+        Err(err) => return report_driver_error(&err),
+    };
+    let cancel = drivers::CancelToken::new();
+    let mut settings = match device.log_settings(&cancel) {
+        Ok(settings) => settings,
+        Err(err) => return report_driver_error(&err),
+    };
+    if change_log_settings(options, &mut settings) {
+        // Read back what the device did.
+        let result = device
+            .set_log_settings(&settings, &cancel)
+            .and_then(|_| device.log_settings(&cancel));
+        settings = match result {
+            Ok(settings) => settings,
+            Err(err) => return report_driver_error(&err),
+        };
+    }
+    print_json(&LogSettingsResult {
+        time_interval: settings.time_interval,
+        distance_interval: settings.distance_interval,
+        speed_threshold: settings.speed_threshold,
+        fields: settings.fields,
+        when_full: settings.when_full.id().to_string(),
+    });
+    EXIT_OK
+}
+
+fn gpsbabel_info() -> i32 {
+    match gpsbabel::probe() {
+        Ok(info) => {
+            print_json(&GpsBabelResult {
+                path: info.path,
+                version: info.version.to_string(),
+                formats: info.formats.len(),
+            });
+            EXIT_OK
+        }
+        Err(err) => report_driver_error(&err),
+    }
+}
+
+/// The gpsbabel timeouts in `options`. 0 is no timeout.
+fn timeouts(options: &Options) -> gpsbabel::Timeouts {
+    let duration = |secs: u64| Some(Duration::from_secs(secs)).filter(|_| secs != 0);
+    let mut timeouts = gpsbabel::Timeouts::DEFAULT;
+    if let Some(timeout) = options.timeout {
+        timeouts.download = duration(timeout);
+        timeouts.erase = duration(timeout);
+        timeouts.command = duration(timeout);
+    }
+    if let Some(stall_timeout) = options.stall_timeout {
+        timeouts.stall = duration(stall_timeout);
+    }
+    timeouts
+}
+
+/// Run the command line. `args` are the program arguments.
+/// Return None if this isn't a command, and the UI should be started.
+/// Otherwise return the exit code.
+pub fn run(args: &[String]) -> Option<i32> {
+    let command = parse_command(args.get(1)?)?;
+    let options = match parse_options(&args[2..]) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return Some(EXIT_USAGE);
+        }
+    };
+    gpsbabel::set_path(options.gpsbabel.as_deref());
+    gpsbabel::set_timeouts(timeouts(&options));
+    let code = match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            EXIT_OK
+        }
+        Command::ListDevices => list_devices(),
+        Command::ListPorts => list_ports(&options),
+        Command::Detect => detect(),
+        Command::History => history(),
+        Command::Download => download(&options),
+        Command::Erase => erase(&options),
+        Command::Logging => logging(&options),
+        Command::Shutoff => shutoff(&options),
+        Command::LogSettings => log_settings(&options),
+        Command::GpsBabel => gpsbabel_info(),
+    };
+    Some(code)
+}
+
+#[test]
+fn test_parse_options() {
+    let args: Vec<String> = [
+        "--model",
+        "m241",
+        "--port=/dev/ttyACM0",
+        "--erase",
+        "--shutoff",
+        "-o",
+        "out.gpx",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    let options = parse_options(&args).unwrap();
+    assert_eq!(options.model, Some("m241".to_string()));
+    assert_eq!(options.port, Some("/dev/ttyACM0".to_string()));
+    assert_eq!(options.output, Some(PathBuf::from("out.gpx")));
+    assert!(options.erase);
+    assert!(!options.no_verify);
+    assert!(options.shutoff);
+    assert!(options.format.is_none());
+    assert_eq!(options.logging, None);
+
+    let args: Vec<String> = [
+        "--output-dir",
+        "/tmp",
+        "--template={model}-{date:%Y}.gpx",
+        "--filter",
+        "merge,shift=-60",
+        "--timeout=0",
+        "--stall-timeout",
+        "30",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    let options = parse_options(&args).unwrap();
+    assert_eq!(options.output_dir, Some(PathBuf::from("/tmp")));
+    assert_eq!(options.template, Some("{model}-{date:%Y}.gpx".to_string()));
+    assert_eq!(options.filter, Some("merge,shift=-60".to_string()));
+    let timeouts = timeouts(&options);
+    assert_eq!(timeouts.download, None);
+    assert_eq!(timeouts.command, None);
+    assert_eq!(timeouts.stall, Some(Duration::from_secs(30)));
+    assert_eq!(timeouts.convert, gpsbabel::Timeouts::DEFAULT.convert);
+
+    let args = vec!["--disable".to_string()];
+    assert_eq!(parse_options(&args).unwrap().logging, Some(false));
+    let args = vec!["--enable".to_string()];
+    assert_eq!(parse_options(&args).unwrap().logging, Some(true));
+
+    let args = vec!["--model".to_string()];
+    assert!(parse_options(&args).is_err());
+    let args = vec!["--timeout".to_string(), "soon".to_string()];
+    assert!(parse_options(&args).is_err());
+    let args = vec!["--bogus".to_string(), "foo".to_string()];
+    assert!(parse_options(&args).is_err());
+}
+
+#[test]
+fn test_log_settings_options() {
+    let args: Vec<String> = [
+        "--interval=5",
+        "--speed",
+        "0",
+        "--fields",
+        "utc,latitude,longitude",
+        "--when-full",
+        "stop",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    let options = parse_options(&args).unwrap();
+    let mut settings = logsettings::LogSettings {
+        time_interval: 1.0,
+        distance_interval: 10.0,
+        speed_threshold: 0.0,
+        fields: vec!["utc".to_string()],
+        when_full: WhenFull::Overwrite,
+    };
+    assert!(change_log_settings(&options, &mut settings));
+    assert_eq!(settings.time_interval, 5.0);
+    assert_eq!(settings.distance_interval, 10.0);
+    assert_eq!(settings.fields.len(), 3);
+    assert_eq!(settings.when_full, WhenFull::Stop);
+    // Already set.
+    assert!(!change_log_settings(&options, &mut settings));
+    assert!(!change_log_settings(&Options::default(), &mut settings));
+
+    let args = vec!["--interval".to_string(), "-1".to_string()];
+    assert!(parse_options(&args).is_err());
+    let args = vec!["--when-full".to_string(), "wrap".to_string()];
+    assert!(parse_options(&args).is_err());
+}
+
+#[test]
+fn test_parse_formats() {
+    assert_eq!(parse_formats("gpx"), Ok(vec![Format::Gpx]));
+    assert_eq!(
+        parse_formats("gpx, kml,tcx"),
+        Ok(vec![Format::Gpx, Format::Kml, Format::Tcx])
+    );
+    assert!(parse_formats("gpx,bogus").is_err());
+    assert!(parse_formats("").is_err());
+}
+
+#[test]
+fn test_run_not_a_command() {
+    assert_eq!(run(&["gpsami".to_string()]), None);
+    assert_eq!(
+        run(&["gpsami".to_string(), "--gapplication-service".to_string()]),
+        None
+    );
+    assert_eq!(parse_command("list-devices"), Some(Command::ListDevices));
+    assert_eq!(parse_command("detect"), Some(Command::Detect));
+    assert_eq!(parse_command("gpsbabel"), Some(Command::GpsBabel));
+    assert_eq!(parse_command("logging"), Some(Command::Logging));
+    assert_eq!(parse_command("shutoff"), Some(Command::Shutoff));
+    assert_eq!(parse_command("log-settings"), Some(Command::LogSettings));
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(
+        exit_code(&drivers::Error::PermissionDenied(String::new())),
+        EXIT_NOPERM
+    );
+    assert_eq!(exit_code(&drivers::Error::NotResponding), EXIT_TEMPFAIL);
+    assert_eq!(exit_code(&drivers::Error::NoDriver), EXIT_UNAVAILABLE);
+    assert_eq!(exit_code(&drivers::Error::Cancelled), EXIT_CANCELLED);
+    assert_eq!(
+        exit_code(&drivers::Error::MissingTool(String::new())),
+        EXIT_UNAVAILABLE
+    );
+}
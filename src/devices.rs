@@ -11,29 +11,127 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use rustc_serialize::json;
 
+use gio::prelude::*;
+
 use crate::drivers;
-use crate::gpsbabel;
+use crate::portaccess::PortAccess;
 
 /// Device static capability
-#[derive(Clone, Debug, RustcDecodable)]
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct Capability {
     pub can_erase: bool,
     pub can_erase_only: bool,
-    can_log_enable: bool,
-    can_shutoff: bool,
+    pub can_log_enable: bool,
+    pub can_shutoff: bool,
+    /// Optional, for the older device databases.
+    can_configure: Option<bool>,
+}
+
+/// USB vendor and product id, in hex.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct UsbId {
+    vendor: String,
+    product: String,
 }
 
 /// Describe a device
-#[derive(Clone, Debug, RustcDecodable)]
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct Desc {
     pub id: String,
     pub label: String,
     cap: Capability,
     driver: String,
+    // USB ids to detect the device.
+    usb: Option<Vec<UsbId>>,
+    // Bluetooth name patterns to detect the device. `*` is a wildcard.
+    bt_names: Option<Vec<String>>,
+}
+
+/// A device detected on a port.
+#[derive(Debug, RustcEncodable)]
+pub struct Detection {
+    pub model: String,
+    pub port: drivers::Port,
+}
+
+/// Match `name` against `pattern` with `*` wildcards, ignoring case.
+fn match_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return name == pattern;
+    }
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl Desc {
+    /// Whether the device on `port` is this one.
+    fn matches(&self, port: &drivers::Port) -> bool {
+        if let (Some(usb), Some(vendor), Some(product)) =
+            (&self.usb, &port.vendor_id, &port.product_id)
+        {
+            if usb.iter().any(|id| {
+                id.vendor.eq_ignore_ascii_case(vendor) && id.product.eq_ignore_ascii_case(product)
+            }) {
+                return true;
+            }
+        }
+        if let (Some(patterns), Some(name)) = (&self.bt_names, &port.bt_name) {
+            return patterns.iter().any(|pattern| match_pattern(pattern, name));
+        }
+        false
+    }
+}
+
+/// Get the name of the Bluetooth device at `address` from BlueZ.
+fn bluetooth_name(address: &str) -> Option<String> {
+    let device = format!("dev_{}", address.replace(':', "_").to_uppercase());
+    let adapters = std::fs::read_dir("/sys/class/bluetooth").ok()?;
+    for adapter in adapters.filter_map(|entry| entry.ok()) {
+        let adapter = adapter.file_name().to_string_lossy().into_owned();
+        // Skip the connections, we want the adapters.
+        if !adapter.starts_with("hci") || adapter.contains(':') {
+            continue;
+        }
+        let object_path = format!("/org/bluez/{}/{}", adapter, device);
+        let proxy = gio::DBusProxy::new_for_bus_sync(
+            gio::BusType::System,
+            gio::DBusProxyFlags::DO_NOT_CONNECT_SIGNALS,
+            None,
+            "org.bluez",
+            &object_path,
+            "org.bluez.Device1",
+            None::<&gio::Cancellable>,
+        );
+        if let Ok(proxy) = proxy {
+            if let Some(name) = proxy
+                .get_cached_property("Name")
+                .and_then(|name| name.get_str().map(|s| s.to_string()))
+            {
+                return Some(name);
+            }
+        }
+    }
+    None
 }
 
 /// The device database.
@@ -43,12 +141,148 @@ struct DeviceDb {
     drivers: Vec<drivers::Desc>,
 }
 
+/// An additional device database file, merged into the built-in one.
+/// Both lists are optional.
+#[derive(Clone, Debug, RustcDecodable)]
+struct DeviceDbFile {
+    devices: Option<Vec<Desc>>,
+    drivers: Option<Vec<drivers::Desc>>,
+}
+
+/// Error loading a device database file.
+#[derive(Debug)]
+pub struct DbError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// An entry of the database, identified by its id.
+trait Entry {
+    fn id(&self) -> &str;
+}
+
+impl Entry for Desc {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Entry for drivers::Desc {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Merge `entries` into `list`, replacing the entries with the same id.
+fn merge_by_id<T: Entry>(list: &mut Vec<T>, entries: Vec<T>) {
+    for entry in entries {
+        match list.iter_mut().find(|e| e.id() == entry.id()) {
+            Some(existing) => *existing = entry,
+            None => list.push(entry),
+        }
+    }
+}
+
+impl DeviceDb {
+    /// Load the built-in database.
+    fn builtin() -> Result<Self, DbError> {
+        json::decode(include_str!("devices.json")).map_err(|e| DbError {
+            path: PathBuf::from("devices.json"),
+            message: e.to_string(),
+        })
+    }
+
+    /// Merge the database file into this one. Entries override
+    /// the existing ones by id.
+    fn merge(&mut self, file: DeviceDbFile) {
+        if let Some(devices) = file.devices {
+            merge_by_id(&mut self.devices, devices);
+        }
+        if let Some(drivers) = file.drivers {
+            merge_by_id(&mut self.drivers, drivers);
+        }
+    }
+
+    /// Load a database file.
+    fn load_file(path: &Path) -> Result<DeviceDbFile, DbError> {
+        let content = fs::read_to_string(path).map_err(|e| DbError {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })?;
+        json::decode(&content).map_err(|e| DbError {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Merge all the `*.json` files in `dir`, in name order.
+    /// Return the errors for the files that couldn't be loaded.
+    fn merge_dir(&mut self, dir: &Path) -> Vec<DbError> {
+        let mut errors = vec![];
+        let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+                .collect(),
+            // The directory not existing is fine.
+            Err(_) => return errors,
+        };
+        files.sort();
+        for file in files {
+            match Self::load_file(&file) {
+                Ok(db) => self.merge(db),
+                Err(e) => errors.push(e),
+            }
+        }
+        errors
+    }
+
+    /// Load the built-in database, and merge the `devices.d` directories
+    /// from `data_dirs`, lowest priority first.
+    fn load(data_dirs: &[PathBuf]) -> (Self, Vec<DbError>) {
+        let mut errors = vec![];
+        let mut db = Self::builtin().unwrap_or_else(|e| {
+            errors.push(e);
+            DeviceDb {
+                devices: vec![],
+                drivers: vec![],
+            }
+        });
+        for dir in data_dirs {
+            let mut dir = dir.clone();
+            dir.push("gpsami");
+            dir.push("devices.d");
+            errors.append(&mut db.merge_dir(&dir));
+        }
+        (db, errors)
+    }
+
+    /// The data directories to look for database files,
+    /// lowest priority first: the system ones, then the user one.
+    fn data_dirs() -> Vec<PathBuf> {
+        // XDG_DATA_DIRS are in decreasing order of priority.
+        let mut dirs: Vec<PathBuf> = glib::get_system_data_dirs().into_iter().rev().collect();
+        if let Some(dir) = glib::get_user_data_dir() {
+            dirs.push(dir);
+        }
+        dirs
+    }
+}
+
 /// The device manager. Where the magic happens.
 pub struct Manager {
     model: Option<String>,
     port: Option<String>,
     devices: Vec<Desc>,
     drivers: Vec<drivers::Desc>,
+    db_errors: Vec<DbError>,
+    registry: drivers::Registry,
 
     udev_context: libudev::Context,
     pub gudev_client: gudev::Client, // gudev client. We need to keep it alive.
@@ -57,7 +291,7 @@ pub struct Manager {
 
 impl Manager {
     pub fn new() -> Self {
-        let devices_db: DeviceDb = json::decode(include_str!("devices.json")).unwrap();
+        let (devices_db, db_errors) = DeviceDb::load(&DeviceDb::data_dirs());
 
         let client = gudev::Client::new(&["tty"]);
 
@@ -71,6 +305,8 @@ impl Manager {
             port: None,
             devices: devices_db.devices,
             drivers: devices_db.drivers,
+            db_errors,
+            registry: drivers::Registry::new(),
             udev_context: context.unwrap(),
             gudev_client: client,
             device_filter: None,
@@ -92,10 +328,23 @@ impl Manager {
         self.port = Some(port.to_owned());
     }
 
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    pub fn port(&self) -> Option<&str> {
+        self.port.as_deref()
+    }
+
     pub fn devices_desc(&self) -> &Vec<Desc> {
         &self.devices
     }
 
+    /// The errors that happened loading the device databases.
+    pub fn db_errors(&self) -> &[DbError] {
+        &self.db_errors
+    }
+
     pub fn device_capability(&self, model: &str) -> Option<Capability> {
         if model.is_empty() {
             return None;
@@ -154,7 +403,24 @@ impl Manager {
                         Some(s) => s.to_string_lossy().into_owned(),
                         None => String::from("(Unknown)"),
                     };
-                    drivers::Port { id, label, path }
+                    let property = |name| {
+                        dev.property_value(name)
+                            .map(|s| s.to_string_lossy().into_owned())
+                    };
+                    // rfcomm ports have the remote address.
+                    let bt_name = dev
+                        .attribute_value("address")
+                        .and_then(|address| bluetooth_name(&address.to_string_lossy()));
+                    let access = Some(PortAccess::check(&path));
+                    drivers::Port {
+                        id,
+                        label,
+                        path,
+                        vendor_id: property("ID_VENDOR_ID"),
+                        product_id: property("ID_MODEL_ID"),
+                        bt_name,
+                        access,
+                    }
                 })
                 .collect();
             dv.append(&mut dv2);
@@ -177,6 +443,24 @@ impl Manager {
         }
     }
 
+    /// Detect the known devices connected.
+    pub fn detect(&self) -> Vec<Detection> {
+        let ports = self.list_ports(vec![
+            drivers::PortType::UsbSerial,
+            drivers::PortType::RfComm,
+        ]);
+        let mut detections = vec![];
+        for port in ports {
+            for device in self.devices.iter().filter(|device| device.matches(&port)) {
+                detections.push(Detection {
+                    model: device.id.clone(),
+                    port: port.clone(),
+                });
+            }
+        }
+        detections
+    }
+
     pub fn get_ports_for_model(&self, model: &str) -> Option<Vec<drivers::Port>> {
         let port_filter = self.get_port_filter_for_model(model);
         Some(self.list_ports(port_filter))
@@ -184,29 +468,18 @@ impl Manager {
 
     // Get a driver for the device from the current manager.
     pub fn get_device(&self) -> Option<Arc<dyn drivers::Driver + Send + Sync>> {
-        if self.model == None {
+        let model = self.model.as_ref()?;
+        let port = self.port.as_ref()?;
+        let device = self.devices.iter().find(|&device| &device.id == model)?;
+        let driver = self
+            .drivers
+            .iter()
+            .find(|&driver| driver.id == device.driver)?;
+        if !self.registry.has_backend(driver.backend()) {
+            eprintln!("Unknown backend {} for {}", driver.backend(), driver.id);
             return None;
         }
-        let capability: Capability;
-        let driver_id = match self.devices.iter().find(|&device| {
-            if let Some(ref model) = self.model {
-                return &device.id == model;
-            }
-            false
-        }) {
-            Some(device) => {
-                capability = device.cap.clone();
-                device.driver.clone()
-            }
-            None => return None,
-        };
-        match driver_id.as_str() {
-            "baroiq" | "dg-100" | "dg-200" | "navilink" | "m241" | "mtk" => match self.port {
-                Some(ref p) => Some(Arc::new(gpsbabel::GpsBabel::new(driver_id, p, capability))),
-                _ => None,
-            },
-            _ => None,
-        }
+        self.registry.create(driver, port, device.cap.clone())
     }
 }
 
@@ -217,8 +490,14 @@ impl Capability {
     //            can_erase_only: false,
     //            can_log_enable: false,
     //            can_shutoff: false,
+    //            can_configure: None,
     //        }
     //    }
+
+    /// The logging settings can be read and changed.
+    pub fn can_configure(&self) -> bool {
+        self.can_configure.unwrap_or(false)
+    }
 }
 
 #[cfg(test)]
@@ -227,4 +506,110 @@ fn test_database() {
     // This test that the database has a valid syntax....
     let devices_db: DeviceDb = json::decode(include_str!("devices.json")).unwrap();
     assert!(!devices_db.devices.is_empty());
+    let m241 = devices_db.devices.iter().find(|d| d.id == "m241").unwrap();
+    assert!(m241.cap.can_configure());
+}
+
+#[test]
+fn test_database_drivers() {
+    // All the devices must have a driver with a known backend.
+    let devices_db = DeviceDb::builtin().unwrap();
+    let registry = drivers::Registry::new();
+    for device in &devices_db.devices {
+        let driver = devices_db
+            .drivers
+            .iter()
+            .find(|&driver| driver.id == device.driver);
+        assert!(driver.is_some(), "No driver for {}", device.id);
+        assert!(registry.has_backend(driver.unwrap().backend()));
+    }
+}
+
+#[test]
+fn test_database_merge() {
+    let mut data_dir = std::env::temp_dir();
+    data_dir.push(format!("gpsami-test-db-{}", std::process::id()));
+    let mut db_dir = data_dir.clone();
+    db_dir.push("gpsami");
+    db_dir.push("devices.d");
+    fs::create_dir_all(&db_dir).unwrap();
+
+    let mut path = db_dir.clone();
+    path.push("10-override.json");
+    fs::write(
+        &path,
+        r#"{
+          "devices": [
+            { "id": "m241", "label": "My M-241", "driver": "m241",
+              "cap": { "can_erase": false, "can_erase_only": false,
+                       "can_log_enable": false, "can_shutoff": false } },
+            { "id": "newdev", "label": "New device", "driver": "newdrv",
+              "cap": { "can_erase": false, "can_erase_only": false,
+                       "can_log_enable": false, "can_shutoff": false } }
+          ],
+          "drivers": [ { "id": "newdrv", "ports": [ "UsbSerial" ],
+                         "backend": "gpsbabel" } ]
+        }"#,
+    )
+    .unwrap();
+    let mut path = db_dir.clone();
+    path.push("20-broken.json");
+    fs::write(&path, "{ \"devices\": [ ").unwrap();
+    // Not a json file, ignored.
+    let mut path = db_dir.clone();
+    path.push("README");
+    fs::write(&path, "not json").unwrap();
+
+    let builtin = DeviceDb::builtin().unwrap();
+    let (db, errors) = DeviceDb::load(&[data_dir.clone()]);
+    fs::remove_dir_all(&data_dir).unwrap();
+
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].path.ends_with("20-broken.json"));
+    assert_eq!(db.devices.len(), builtin.devices.len() + 1);
+    assert_eq!(db.drivers.len(), builtin.drivers.len() + 1);
+    let m241 = db.devices.iter().find(|d| d.id == "m241").unwrap();
+    assert_eq!(m241.label, "My M-241");
+    assert!(!m241.cap.can_erase);
+}
+
+#[test]
+fn test_match_pattern() {
+    assert!(match_pattern("HOLUX_M-241", "holux_m-241"));
+    assert!(match_pattern("HOLUX_M-241*", "HOLUX_M-241 #2"));
+    assert!(match_pattern("*241*", "HOLUX_M-241 #2"));
+    assert!(match_pattern("H*X*1", "HOLUX_M-241"));
+    assert!(match_pattern("*", "anything"));
+    assert!(!match_pattern("HOLUX_M-241", "HOLUX_M-241 #2"));
+    assert!(!match_pattern("WBT*", "HOLUX_M-241"));
+    assert!(!match_pattern("*x*x", "x"));
+}
+
+#[test]
+fn test_detect_match() {
+    let devices_db = DeviceDb::builtin().unwrap();
+    let m241 = devices_db.devices.iter().find(|d| d.id == "m241").unwrap();
+    let m1200e = devices_db
+        .devices
+        .iter()
+        .find(|d| d.id == "m1200e")
+        .unwrap();
+    let mut port = drivers::Port {
+        id: "ttyACM0".to_string(),
+        label: "".to_string(),
+        path: PathBuf::from("/dev/ttyACM0"),
+        vendor_id: Some("0E8D".to_string()),
+        product_id: Some("3329".to_string()),
+        bt_name: None,
+        access: None,
+    };
+    assert!(m1200e.matches(&port));
+    port.product_id = Some("0000".to_string());
+    assert!(!m1200e.matches(&port));
+    // A generic USB serial adapter is no device.
+    port.vendor_id = Some("067B".to_string());
+    port.product_id = Some("2303".to_string());
+    assert!(devices_db.devices.iter().all(|d| !d.matches(&port)));
+    port.bt_name = Some("HOLUX_M-241".to_string());
+    assert!(m241.matches(&port));
 }
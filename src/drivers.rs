@@ -14,14 +14,29 @@
 use std::fmt;
 use std::io;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use crate::devices::Capability;
+use crate::gpsbabel;
+use crate::logsettings::LogSettings;
+use crate::mtk;
+use crate::portaccess::PortAccess;
+use crate::tempfile::TempFile;
 use crate::Format;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, RustcEncodable)]
 pub struct Port {
     pub id: String,
     pub label: String,
     pub path: PathBuf,
+    // USB vendor and product id, in hex.
+    pub vendor_id: Option<String>,
+    pub product_id: Option<String>,
+    // Name of the remote Bluetooth device.
+    pub bt_name: Option<String>,
+    // Whether the user can use it.
+    pub access: Option<PortAccess>,
 }
 
 #[derive(Copy, Clone, Debug, RustcDecodable)]
@@ -36,40 +51,454 @@ pub struct Desc {
     pub id: String,
     // the port to look for.
     pub ports: Vec<PortType>,
+    // the backend implementing the driver, from the Registry.
+    // Default to gpsbabel.
+    backend: Option<String>,
+    // the protocol for the backend. Default to the id.
+    pub format: Option<String>,
+    // extra options for the backend.
+    pub options: Option<Vec<String>>,
+}
+
+impl Desc {
+    /// The backend implementing the driver.
+    pub fn backend(&self) -> &str {
+        self.backend.as_deref().unwrap_or("gpsbabel")
+    }
+
+    /// The protocol format for the backend.
+    pub fn format(&self) -> &str {
+        self.format.as_ref().unwrap_or(&self.id)
+    }
+
+    /// The extra options for the backend.
+    pub fn options(&self) -> &[String] {
+        self.options.as_deref().unwrap_or(&[])
+    }
 }
 
+/// The errors of the drivers. The message tells the user what to do.
+#[derive(Debug)]
 pub enum Error {
-    Success,
+    /// The operation isn't supported by the device.
     Unsupported,
     NoDriver,
     Cancelled,
     WrongArg,
+    /// The port is used by another program.
+    PortBusy(String),
+    /// The user isn't allowed to use the port.
+    PermissionDenied(String),
+    /// The device doesn't answer.
+    NotResponding,
+    /// The device answered something unexpected.
+    Protocol(String),
+    /// A tool, gpsbabel, is missing or can't be used.
+    MissingTool(String),
+    /// The operation took too long, or the device stopped responding.
+    Timeout(String),
     Failed(String),
     IOError(io::Error),
 }
 
+impl Error {
+    /// The error for `err`, from using `port`.
+    pub fn from_port_error(err: io::Error, port: &str) -> Error {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(port.to_string()),
+            io::ErrorKind::TimedOut => Error::NotResponding,
+            _ if err.raw_os_error() == Some(libc::EBUSY) => Error::PortBusy(port.to_string()),
+            _ => Error::IOError(err),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::Success => write!(f, "Success"),
-            Error::Unsupported => write!(f, "Unsupported"),
-            Error::NoDriver => write!(f, "No driver"),
+            Error::Unsupported => write!(f, "The device doesn't support this operation."),
+            Error::NoDriver => write!(f, "No driver for this device."),
             Error::Cancelled => write!(f, "Cancelled"),
-            Error::WrongArg => write!(f, "WrongArg"),
+            Error::WrongArg => write!(f, "Invalid argument"),
+            Error::PortBusy(ref port) => write!(
+                f,
+                "{} is used by another program, like ModemManager or gpsd. \
+                 Stop it, or unplug the device and plug it back, and try again.",
+                port
+            ),
+            Error::PermissionDenied(ref port) => write!(
+                f,
+                "You aren't allowed to use {}. Add yourself to the dialout \
+                 group (uucp on some systems), log out and log in again.",
+                port
+            ),
+            Error::NotResponding => write!(
+                f,
+                "The device doesn't respond. Check that it is turned on and \
+                 connected, and try again."
+            ),
+            Error::Protocol(ref s) => write!(f, "The device sent unexpected data: {}", s),
+            Error::MissingTool(ref s) => write!(f, "{}", s),
+            Error::Timeout(ref s) => write!(f, "{}", s),
             Error::Failed(ref s) => write!(f, "{}", s),
             Error::IOError(ref e) => write!(f, "{}", e),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::IOError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The phase of an operation, for progress reporting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Connecting,
+    Downloading,
+    Erasing,
+    Writing,
+}
+
+impl Phase {
+    /// Human readable label for the phase.
+    pub fn label(self) -> &'static str {
+        match self {
+            Phase::Connecting => "Connecting",
+            Phase::Downloading => "Downloading",
+            Phase::Erasing => "Erasing",
+            Phase::Writing => "Writing",
+        }
+    }
+}
+
+/// Progress of an operation.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Units (bytes, records or percent) done so far.
+    pub done: u64,
+    /// Total units, if known.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(phase: Phase, done: u64, total: Option<u64>) -> Self {
+        Progress { phase, done, total }
+    }
+
+    /// The fraction done, between 0.0 and 1.0. None if the total is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(total) if total > 0 => Some((self.done as f64 / total as f64).min(1.0)),
+            _ => None,
+        }
+    }
+}
+
+/// Callback the drivers call to report progress.
+pub type ProgressFn = dyn Fn(Progress) + Send + Sync;
+
+/// Token to cancel a running operation. Clones share the state,
+/// so one can be kept to cancel while the driver checks the other.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the cancellation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Create a driver for the description, port and device capability.
+pub type Factory = fn(&Desc, &str, Capability) -> Arc<dyn Driver + Send + Sync>;
+
+/// The registry of driver backends, by name.
+pub struct Registry {
+    backends: Vec<(&'static str, Factory)>,
+}
+
+impl Registry {
+    /// Create the registry with all the backends.
+    pub fn new() -> Self {
+        let mut registry = Registry { backends: vec![] };
+        registry.register("gpsbabel", gpsbabel::GpsBabel::create);
+        registry.register("mtk", mtk::Mtk::create);
+        registry
+    }
+
+    pub fn register(&mut self, name: &'static str, factory: Factory) {
+        self.backends.push((name, factory));
+    }
+
+    pub fn has_backend(&self, name: &str) -> bool {
+        self.backends.iter().any(|&(n, _)| n == name)
+    }
+
+    /// Create the driver described by `desc` for `port`.
+    /// Return None if the backend doesn't exist.
+    pub fn create(
+        &self,
+        desc: &Desc,
+        port: &str,
+        capability: Capability,
+    ) -> Option<Arc<dyn Driver + Send + Sync>> {
+        self.backends
+            .iter()
+            .find(|&&(name, _)| name == desc.backend())
+            .map(|&(_, factory)| factory(desc, port, capability))
+    }
+}
+
 pub trait Driver {
     /// open the device
-    fn open(&mut self) -> bool;
+    fn open(&mut self) -> Result<(), Error>;
     /// close the device
     fn close(&mut self) -> bool;
     /// Download the track in specified format
-    /// Return the PathBuf pointing to the datafile.
-    fn download(&self, format: Format, erase: bool) -> Result<PathBuf, Error>;
+    /// Return the temporary file with the data.
+    /// `progress` is called as the download goes.
+    /// Will stop with `Error::Cancelled` if `cancel` is cancelled.
+    fn download(
+        &self,
+        format: Format,
+        erase: bool,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<TempFile, Error>;
     /// Erase the tracks
-    fn erase(&self) -> Result<(), Error>;
+    fn erase(&self, progress: &ProgressFn, cancel: &CancelToken) -> Result<(), Error>;
+    /// Start or stop logging.
+    fn set_logging(
+        &self,
+        _enable: bool,
+        _progress: &ProgressFn,
+        _cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+    /// Whether the device is logging.
+    fn logging(&self) -> Result<bool, Error> {
+        Err(Error::Unsupported)
+    }
+    /// Turn the device off.
+    fn shutoff(&self, _progress: &ProgressFn, _cancel: &CancelToken) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+    /// The logging settings of the device.
+    fn log_settings(&self, _cancel: &CancelToken) -> Result<LogSettings, Error> {
+        Err(Error::Unsupported)
+    }
+    /// Change the logging settings of the device.
+    fn set_log_settings(
+        &self,
+        _settings: &LogSettings,
+        _cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Download from `driver` in all the `formats`, reading the device once.
+/// The device is read as GPX, filtered by gpsbabel with `filters` if
+/// any, and converted by gpsbabel to the other formats. Return the
+/// temporary files in the order of `formats`, without duplicates.
+/// The device can only be erased while downloading if nothing follows,
+/// as the data would be lost if gpsbabel failed.
+pub fn download_formats(
+    driver: &dyn Driver,
+    formats: &[Format],
+    filters: &[gpsbabel::Filter],
+    erase: bool,
+    progress: &ProgressFn,
+    cancel: &CancelToken,
+) -> Result<Vec<(Format, TempFile)>, Error> {
+    let mut unique: Vec<Format> = vec![];
+    for format in formats {
+        if !unique.contains(format) {
+            unique.push(*format);
+        }
+    }
+    if unique.is_empty() || unique.contains(&Format::None) {
+        return Err(Error::WrongArg);
+    }
+    if erase && (unique != [Format::Gpx] || !filters.is_empty()) {
+        return Err(Error::WrongArg);
+    }
+
+    let mut gpx = driver.download(Format::Gpx, erase, progress, cancel)?;
+    if !filters.is_empty() {
+        gpx = gpsbabel::GpsBabel::convert(gpx.path(), Format::Gpx, filters, progress, cancel)?;
+    }
+    let mut converted = unique
+        .iter()
+        .filter(|format| **format != Format::Gpx)
+        .map(|format| {
+            gpsbabel::GpsBabel::convert(gpx.path(), *format, &[], progress, cancel)
+                .map(|temp| (*format, temp))
+        })
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_iter();
+    let mut gpx = Some(gpx);
+    Ok(unique
+        .iter()
+        .filter_map(|format| match format {
+            Format::Gpx => gpx.take().map(|temp| (Format::Gpx, temp)),
+            _ => converted.next(),
+        })
+        .collect())
+}
+
+/// Turn `device` off once the download is saved. The download isn't
+/// lost if this fails.
+pub fn shutoff_after_download(
+    device: &dyn Driver,
+    progress: &ProgressFn,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    device.shutoff(progress, cancel).map_err(|err| {
+        Error::Failed(format!(
+            "The data was downloaded, but the device wasn't turned off: {}",
+            err
+        ))
+    })
+}
+
+#[test]
+fn test_progress() {
+    let progress = Progress::new(Phase::Downloading, 50, Some(200));
+    assert_eq!(progress.fraction(), Some(0.25));
+
+    let progress = Progress::new(Phase::Downloading, 50, None);
+    assert_eq!(progress.fraction(), None);
+
+    let progress = Progress::new(Phase::Erasing, 0, Some(0));
+    assert_eq!(progress.fraction(), None);
+}
+
+/// A driver downloading an empty GPX, that can't be turned off.
+#[cfg(test)]
+struct FakeDriver;
+
+#[cfg(test)]
+impl Driver for FakeDriver {
+    fn open(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn close(&mut self) -> bool {
+        true
+    }
+    fn download(
+        &self,
+        format: Format,
+        _: bool,
+        _: &ProgressFn,
+        _: &CancelToken,
+    ) -> Result<TempFile, Error> {
+        assert_eq!(format, Format::Gpx);
+        let temp = TempFile::new("fake.gpx").map_err(Error::IOError)?;
+        std::fs::write(temp.path(), "<gpx></gpx>").map_err(Error::IOError)?;
+        Ok(temp)
+    }
+    fn erase(&self, _: &ProgressFn, _: &CancelToken) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_download_formats() {
+    let cancel = CancelToken::new();
+    let progress = |_| {};
+    let result = download_formats(
+        &FakeDriver,
+        &[Format::Gpx, Format::Gpx],
+        &[],
+        false,
+        &progress,
+        &cancel,
+    )
+    .unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].0, Format::Gpx);
+    assert!(result[0].1.path().exists());
+
+    assert!(download_formats(&FakeDriver, &[], &[], false, &progress, &cancel).is_err());
+    assert!(
+        download_formats(&FakeDriver, &[Format::None], &[], false, &progress, &cancel).is_err()
+    );
+    // Not erased before the conversion.
+    assert!(matches!(
+        download_formats(&FakeDriver, &[Format::Kml], &[], true, &progress, &cancel),
+        Err(Error::WrongArg)
+    ));
+}
+
+#[test]
+fn test_shutoff_after_download() {
+    let cancel = CancelToken::new();
+    let progress = |_| {};
+    let err = shutoff_after_download(&FakeDriver, &progress, &cancel).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The data was downloaded, but the device wasn't turned off: \
+         The device doesn't support this operation."
+    );
+    cancel.cancel();
+    assert!(matches!(
+        shutoff_after_download(&FakeDriver, &progress, &cancel),
+        Err(Error::Cancelled)
+    ));
+}
+
+#[test]
+fn test_port_error() {
+    let port = "/dev/ttyUSB0";
+    let err = Error::from_port_error(io::Error::from_raw_os_error(libc::EACCES), port);
+    assert!(matches!(err, Error::PermissionDenied(_)));
+    assert!(err.to_string().contains("dialout"));
+    let err = Error::from_port_error(io::Error::from_raw_os_error(libc::EBUSY), port);
+    assert!(matches!(err, Error::PortBusy(_)));
+    assert!(err.to_string().starts_with("/dev/ttyUSB0 is used"));
+    let err = Error::from_port_error(io::Error::new(io::ErrorKind::TimedOut, "timeout"), port);
+    assert!(matches!(err, Error::NotResponding));
+    let err = Error::from_port_error(io::Error::from_raw_os_error(libc::ENOENT), port);
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn test_desc_backend() {
+    let desc: Desc = rustc_serialize::json::decode(
+        r#"{ "id": "m241", "ports": [ "UsbSerial" ], "backend": "mtk" }"#,
+    )
+    .unwrap();
+    assert_eq!(desc.backend(), "mtk");
+    // Older databases have no backend.
+    let desc: Desc =
+        rustc_serialize::json::decode(r#"{ "id": "navilink", "ports": [ "UsbSerial" ] }"#).unwrap();
+    assert_eq!(desc.backend(), "gpsbabel");
+}
+
+#[test]
+fn test_cancel_token() {
+    let token = CancelToken::new();
+    let token2 = token.clone();
+    assert!(!token2.is_cancelled());
+    token.cancel();
+    assert!(token2.is_cancelled());
 }
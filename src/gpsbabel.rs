@@ -12,59 +12,514 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::env;
-use std::path::PathBuf;
-use std::process::Command;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::devices::Capability;
-use crate::drivers::Driver;
+use crate::drivers;
 use crate::drivers::Error;
+use crate::drivers::{CancelToken, Driver, Phase, Progress, ProgressFn};
+use crate::tempfile::TempFile;
 use crate::Format;
 
+/// How often to check for cancellation while gpsbabel runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The oldest gpsbabel supported.
+const MIN_VERSION: Version = Version(1, 5, 0);
+
+/// The gpsbabel set in the settings. None to look for it in `PATH`.
+static CONFIGURED_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// The last gpsbabel probed, to not run it again for each operation.
+static PROBED: Mutex<Option<Info>> = Mutex::new(None);
+/// The timeouts set in the settings.
+static TIMEOUTS: Mutex<Timeouts> = Mutex::new(Timeouts::DEFAULT);
+
+/// How long gpsbabel can run before it is killed. None for no limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    pub download: Option<Duration>,
+    pub erase: Option<Duration>,
+    /// Converting to another format or filtering.
+    pub convert: Option<Duration>,
+    /// The other commands to the device, like turning it off, run
+    /// while connecting.
+    pub command: Option<Duration>,
+    /// How long a download or a command can go without any progress
+    /// from gpsbabel, once the device stopped responding.
+    pub stall: Option<Duration>,
+}
+
+impl Timeouts {
+    pub const DEFAULT: Timeouts = Timeouts {
+        download: Some(Duration::from_secs(3600)),
+        erase: Some(Duration::from_secs(600)),
+        convert: Some(Duration::from_secs(600)),
+        command: Some(Duration::from_secs(600)),
+        stall: Some(Duration::from_secs(120)),
+    };
+
+    /// The timeout of the operation `phase`.
+    fn for_phase(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Downloading => self.download,
+            Phase::Erasing => self.erase,
+            Phase::Writing => self.convert,
+            Phase::Connecting => self.command,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts::DEFAULT
+    }
+}
+
+/// Set the timeouts of the next operations.
+pub fn set_timeouts(timeouts: Timeouts) {
+    *TIMEOUTS.lock().unwrap() = timeouts;
+}
+
+/// The timeouts of the operations.
+pub fn timeouts() -> Timeouts {
+    *TIMEOUTS.lock().unwrap()
+}
+
+/// Watch an operation for its timeouts.
+struct Watchdog {
+    phase: Phase,
+    timeout: Option<Duration>,
+    /// Only downloads and commands, which read the device, stall: the
+    /// other operations may not output anything while they run.
+    stall: Option<Duration>,
+    start: Instant,
+    /// When the last progress was reported. gpsbabel doesn't report
+    /// any before the download starts, so the stall is only checked
+    /// after the first one.
+    last_progress: Option<Instant>,
+}
+
+impl Watchdog {
+    fn new(timeouts: &Timeouts, phase: Phase, now: Instant) -> Watchdog {
+        Watchdog {
+            phase,
+            timeout: timeouts.for_phase(phase),
+            stall: timeouts
+                .stall
+                .filter(|_| phase == Phase::Downloading || phase == Phase::Connecting),
+            start: now,
+            last_progress: None,
+        }
+    }
+
+    /// gpsbabel reported progress at `now`.
+    fn progressed(&mut self, now: Instant) {
+        self.last_progress = Some(now);
+    }
+
+    /// Check the timeouts at `now`.
+    fn check(&self, now: Instant) -> Result<(), Error> {
+        if let Some(timeout) = self.timeout {
+            if now.duration_since(self.start) > timeout {
+                return Err(Error::Timeout(format!(
+                    "{} took longer than {} seconds.",
+                    self.phase.label(),
+                    timeout.as_secs()
+                )));
+            }
+        }
+        if let (Some(stall), Some(last_progress)) = (self.stall, self.last_progress) {
+            if now.duration_since(last_progress) > stall {
+                return Err(Error::Timeout(format!(
+                    "The device stopped responding for {} seconds.",
+                    stall.as_secs()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the gpsbabel to use, either a path or a name to look for in
+/// `PATH`. None or empty for the default.
+pub fn set_path(path: Option<&Path>) {
+    let path = path.filter(|path| !path.as_os_str().is_empty());
+    *CONFIGURED_PATH.lock().unwrap() = path.map(Path::to_path_buf);
+}
+
+/// Find the executable `name` in the directories of `path_var`, like
+/// `PATH`. If `name` has a directory, it is used as is.
+fn find_in_path(name: &Path, path_var: Option<&OsStr>) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if name.components().count() > 1 {
+        return Some(name.to_path_buf()).filter(|path| is_executable(path));
+    }
+    env::split_paths(path_var?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+/// Locate gpsbabel.
+fn locate() -> Result<PathBuf, Error> {
+    let configured = CONFIGURED_PATH.lock().unwrap().clone();
+    let name = configured
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("gpsbabel"));
+    find_in_path(&name, env::var_os("PATH").as_deref()).ok_or_else(|| {
+        Error::MissingTool(match configured {
+            Some(path) => format!("gpsbabel wasn't found at {}.", path.display()),
+            None => "gpsbabel wasn't found. Install it, \
+                     or set its path in the settings."
+                .to_string(),
+        })
+    })
+}
+
+/// The error for a failure to run gpsbabel.
+fn spawn_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+            Error::MissingTool(format!("gpsbabel can't be run: {}", err))
+        }
+        _ => Error::IOError(err),
+    }
+}
+
+/// A gpsbabel version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// Parse the output of `gpsbabel -V`, like `GPSBabel Version 1.7.0`.
+    fn parse(output: &str) -> Option<Version> {
+        let mut words = output.split_whitespace();
+        words.find(|word| word.eq_ignore_ascii_case("version"))?;
+        let mut numbers = words
+            .next()?
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|number| number.parse::<u32>());
+        let major = numbers.next()?.ok()?;
+        let minor = numbers.next().and_then(Result::ok).unwrap_or(0);
+        let patch = numbers.next().and_then(Result::ok).unwrap_or(0);
+        Some(Version(major, minor, patch))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// A format gpsbabel supports, for `-i` or `-o`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatInfo {
+    pub name: String,
+    pub read: bool,
+    pub write: bool,
+    /// The names of the options.
+    pub options: Vec<String>,
+}
+
+/// What the gpsbabel installed is and supports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Info {
+    pub path: PathBuf,
+    pub version: Version,
+    pub formats: Vec<FormatInfo>,
+}
+
+impl Info {
+    /// Parse the formats and their options from `gpsbabel -^3`.
+    /// The lines are tab separated: `file`, `serial` or `internal`,
+    /// the read and write flags, and the name; or `option`, the name
+    /// of the format and the name of the option.
+    fn parse_formats(output: &str) -> Vec<FormatInfo> {
+        let mut formats: Vec<FormatInfo> = vec![];
+        for line in output.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["option", format, option, ..] => {
+                    if let Some(info) = formats.iter_mut().find(|info| info.name == *format) {
+                        info.options.push(option.to_string());
+                    }
+                }
+                [_, flags, name, ..] if flags.len() == 6 => {
+                    // rw for waypoints, tracks and routes.
+                    let flags = flags.as_bytes();
+                    formats.push(FormatInfo {
+                        name: name.to_string(),
+                        read: flags.iter().step_by(2).any(|flag| *flag == b'r'),
+                        write: flags.iter().skip(1).step_by(2).any(|flag| *flag == b'w'),
+                        options: vec![],
+                    });
+                }
+                _ => {}
+            }
+        }
+        formats
+    }
+
+    /// Check that the format `name` can be read, with `options`
+    /// (`name=value` or `name`). If the formats are unknown,
+    /// assume it can.
+    pub fn check_read(&self, name: &str, options: &[&str]) -> Result<(), Error> {
+        if self.formats.is_empty() {
+            return Ok(());
+        }
+        let info = self
+            .formats
+            .iter()
+            .find(|info| info.name == name && info.read)
+            .ok_or_else(|| {
+                Error::Failed(format!("gpsbabel {} can't read {}.", self.version, name))
+            })?;
+        for option in options {
+            let option = option.split('=').next().unwrap_or_default();
+            if !info.options.iter().any(|o| o == option) {
+                return Err(Error::Failed(format!(
+                    "gpsbabel {} doesn't support the option {} of {}.",
+                    self.version, option, name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the format `name` can be written.
+    pub fn check_write(&self, name: &str) -> Result<(), Error> {
+        if self.formats.is_empty()
+            || self
+                .formats
+                .iter()
+                .any(|info| info.name == name && info.write)
+        {
+            Ok(())
+        } else {
+            Err(Error::Failed(format!(
+                "gpsbabel {} can't write {}.",
+                self.version, name
+            )))
+        }
+    }
+}
+
+/// Locate gpsbabel, check its version and what it supports.
+/// The result is kept for the next calls.
+pub fn probe() -> Result<Info, Error> {
+    let path = locate()?;
+    if let Some(ref info) = *PROBED.lock().unwrap() {
+        if info.path == path {
+            return Ok(info.clone());
+        }
+    }
+    let output = Command::new(&path)
+        .arg("-V")
+        .output()
+        .map_err(spawn_error)?;
+    let version = Version::parse(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        Error::MissingTool(format!("{} doesn't look like gpsbabel.", path.display()))
+    })?;
+    if version < MIN_VERSION {
+        return Err(Error::MissingTool(format!(
+            "gpsbabel {} is too old, {} or later is needed.",
+            version, MIN_VERSION
+        )));
+    }
+    let output = Command::new(&path)
+        .arg("-^3")
+        .output()
+        .map_err(spawn_error)?;
+    let info = Info {
+        path,
+        version,
+        formats: Info::parse_formats(&String::from_utf8_lossy(&output.stdout)),
+    };
+    *PROBED.lock().unwrap() = Some(info.clone());
+    Ok(info)
+}
+
+/// A gpsbabel filter, applied to the downloaded data.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// Simplify the tracks down to a number of points.
+    SimplifyCount(u32),
+    /// Simplify the tracks, within an error in meters.
+    SimplifyError(f64),
+    /// Shift the time of the track points, in seconds. To correct the
+    /// device clock.
+    TimeShift(i64),
+    /// Remove the duplicate waypoints, at the same location.
+    Dedupe,
+    /// Merge all the tracks into one, in time order.
+    Merge,
+    /// Discard the points with a HDOP over the value.
+    MaxHdop(f64),
+}
+
+impl Filter {
+    /// Parse a filter from the settings or the command line:
+    /// `simplify=500` (points), `simplify=10m` (meters), `shift=-3600`
+    /// (seconds), `dedupe`, `merge` or `hdop=5`.
+    pub fn parse(spec: &str) -> Option<Filter> {
+        let spec = spec.trim();
+        let (name, value) = match spec.find('=') {
+            Some(idx) => (&spec[..idx], Some(spec[idx + 1..].trim())),
+            None => (spec, None),
+        };
+        match (name.trim(), value) {
+            ("simplify", Some(value)) => match value.strip_suffix('m') {
+                Some(meters) => meters
+                    .parse()
+                    .ok()
+                    .filter(|meters: &f64| *meters > 0.0)
+                    .map(Filter::SimplifyError),
+                None => value
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .map(Filter::SimplifyCount),
+            },
+            ("shift", Some(value)) => value
+                .trim_start_matches('+')
+                .parse()
+                .ok()
+                .map(Filter::TimeShift),
+            ("dedupe", None) => Some(Filter::Dedupe),
+            ("merge", None) => Some(Filter::Merge),
+            ("hdop", Some(value)) => value
+                .parse()
+                .ok()
+                .filter(|hdop: &f64| *hdop > 0.0)
+                .map(Filter::MaxHdop),
+            _ => None,
+        }
+    }
+
+    /// Parse a list of filters separated by `sep`. Empty items are
+    /// ignored.
+    pub fn parse_list(specs: &str, sep: char) -> Result<Vec<Filter>, String> {
+        specs
+            .split(sep)
+            .filter(|spec| !spec.trim().is_empty())
+            .map(|spec| Filter::parse(spec).ok_or_else(|| format!("Invalid filter {}", spec)))
+            .collect()
+    }
+
+    /// The gpsbabel `-x` argument.
+    fn to_arg(&self) -> String {
+        match *self {
+            Filter::SimplifyCount(count) => format!("simplify,count={}", count),
+            // gpsbabel wants kilometers.
+            Filter::SimplifyError(meters) => format!("simplify,error={}k", meters / 1000.0),
+            Filter::TimeShift(secs) => format!("track,move={:+}s", secs),
+            Filter::Dedupe => "duplicate,location".to_string(),
+            Filter::Merge => "track,merge".to_string(),
+            Filter::MaxHdop(hdop) => format!("discard,hdop={}", hdop),
+        }
+    }
+}
+
+/// Append the `filters` to the gpsbabel `command`, in order.
+fn push_filters(command: &mut Command, filters: &[Filter]) {
+    for filter in filters {
+        command.arg("-x").arg(filter.to_arg());
+    }
+}
+
 /// GpsBabel "driver". Will use gpsbabel to connect to device.
 pub struct GpsBabel {
     device_id: String,
+    options: Vec<String>,
     port: String,
     cap: Capability,
 }
 
 impl GpsBabel {
-    pub fn new(device: String, port: &str, capability: Capability) -> Self {
+    pub fn new(device: String, options: Vec<String>, port: &str, capability: Capability) -> Self {
         GpsBabel {
             device_id: device,
+            options,
             port: port.to_owned(),
             cap: capability,
         }
     }
 
+    /// Factory for the driver registry.
+    /// The driver format is the gpsbabel `-i` format.
+    pub fn create(
+        desc: &drivers::Desc,
+        port: &str,
+        capability: Capability,
+    ) -> Arc<dyn Driver + Send + Sync> {
+        Arc::new(GpsBabel::new(
+            desc.format().to_string(),
+            desc.options().to_vec(),
+            port,
+            capability,
+        ))
+    }
+
     /// Return a string associated with the format.
     /// Or None
     fn format_to_string(format: &Format) -> Option<&'static str> {
         match *format {
             Format::Gpx => Some("gpx"),
             Format::Kml => Some("kml"),
-            _ => None,
+            Format::GeoJson => Some("geojson"),
+            Format::Tcx => Some("gtrnctr"),
+            Format::Csv => Some("unicsv"),
+            Format::Nmea => Some("nmea"),
+            Format::Ozi => Some("ozi"),
+            Format::Fit => Some("garmin_fit"),
+            Format::None => None,
         }
     }
 
     /// Return an extension (with .) associated with the format.
     /// Or None
-    fn format_to_extension(format: &Format) -> Option<&'static str> {
+    pub fn format_to_extension(format: &Format) -> Option<&'static str> {
         match *format {
             Format::Gpx => Some(".gpx"),
             Format::Kml => Some(".kml"),
-            _ => None,
+            Format::GeoJson => Some(".geojson"),
+            Format::Tcx => Some(".tcx"),
+            Format::Csv => Some(".csv"),
+            Format::Nmea => Some(".nmea"),
+            Format::Ozi => Some(".plt"),
+            Format::Fit => Some(".fit"),
+            Format::None => None,
         }
     }
 
     /// Build the basic command line for the device on port, eventually for delete
     /// after download or erase only.
     fn build_basic_command_line(
+        program: &Path,
         device_id: &str,
+        options: &[String],
         port: &str,
         erase: bool,
         erase_only: bool,
     ) -> Command {
         let mut device_string = String::from(device_id);
+        for option in options {
+            device_string.push(',');
+            device_string.push_str(option);
+        }
         // erase and erase_only can't be true at the same time.
         // assume that if erase is true, then we want a download.
         if erase {
@@ -72,8 +527,10 @@ impl GpsBabel {
         } else if erase_only {
             device_string.push_str(",erase_only");
         }
-        let mut command = Command::new("gpsbabel");
+        let mut command = Command::new(program);
+        // The options apply to what follows: -vs first for the status.
         command
+            .arg("-vs")
             .arg("-t")
             .arg("-w")
             .arg("-i")
@@ -83,20 +540,280 @@ impl GpsBabel {
 
         command
     }
+
+    /// Build the command line to convert the GPX file `source` to the
+    /// gpsbabel format `fmt` into `dest`, applying the `filters`.
+    fn build_convert_command_line(
+        program: &Path,
+        source: &Path,
+        fmt: &str,
+        filters: &[Filter],
+        dest: &Path,
+    ) -> Command {
+        let mut command = Command::new(program);
+        command
+            .arg("-vs")
+            .arg("-t")
+            .arg("-w")
+            .arg("-i")
+            .arg("gpx")
+            .arg("-f")
+            .arg(source);
+        push_filters(&mut command, filters);
+        command.arg("-o").arg(fmt).arg("-F").arg(dest);
+
+        command
+    }
+
+    /// Convert the GPX file `source` to `format`, applying the `filters`.
+    /// Return the temporary file with the data.
+    pub fn convert(
+        source: &Path,
+        format: Format,
+        filters: &[Filter],
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<TempFile, Error> {
+        let fmt_string = Self::format_to_string(&format).ok_or(Error::WrongArg)?;
+        let extension = Self::format_to_extension(&format).ok_or(Error::WrongArg)?;
+        let info = probe()?;
+        info.check_read("gpx", &[])?;
+        info.check_write(fmt_string)?;
+        let temp = TempFile::new(&(String::from("gpsami") + extension)).map_err(Error::IOError)?;
+        let command =
+            Self::build_convert_command_line(&info.path, source, fmt_string, filters, temp.path());
+        Self::run(command, Phase::Writing, &timeouts(), progress, cancel)?;
+        if !temp.path().exists() {
+            return Err(Error::Failed("gpsbabel didn't output any data".to_string()));
+        }
+        Ok(temp)
+    }
+
+    /// Parse a progress line from gpsbabel status output.
+    /// Understands percentages ("42%"), counts ("120/4000") and the
+    /// `-vs` status, counts and percentage ("120/4000/3").
+    /// Return the done and total units.
+    fn parse_progress(line: &str) -> Option<(u64, Option<u64>)> {
+        let line = line.trim();
+        if let Some(percent) = line.strip_suffix('%') {
+            let percent = percent.rsplit(' ').next()?;
+            return percent.trim().parse::<u64>().ok().map(|p| (p, Some(100)));
+        }
+        let parts: Vec<&str> = line.split('/').map(str::trim).collect();
+        if parts.len() == 2 || parts.len() == 3 {
+            let done = parts[0].parse::<u64>().ok()?;
+            let total = parts[1].parse::<u64>().ok()?;
+            if parts.len() == 3 {
+                parts[2].parse::<u64>().ok()?;
+            }
+            return Some((done, Some(total)));
+        }
+        None
+    }
+
+    /// Run the gpsbabel command, reporting the progress for `phase`.
+    /// The status output, on stdout with `-vs`, is parsed for progress,
+    /// everything else is collected as the error message.
+    /// gpsbabel is killed if `cancel` is cancelled, or on `timeouts`.
+    fn run(
+        mut command: Command,
+        phase: Phase,
+        timeouts: &Timeouts,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        progress(Progress::new(Phase::Connecting, 0, None));
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error)?;
+
+        // Read stdout and stderr in threads so that we can check for
+        // cancellation. The data is sent with the index of the stream.
+        let (tx, rx) = mpsc::channel::<(usize, Vec<u8>)>();
+        let streams: Vec<Option<Box<dyn Read + Send>>> = vec![
+            child
+                .stdout
+                .take()
+                .map(|stdout| Box::new(stdout) as Box<dyn Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|stderr| Box::new(stderr) as Box<dyn Read + Send>),
+        ];
+        for (index, stream) in streams.into_iter().enumerate() {
+            if let Some(mut stream) = stream {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut buf = [0_u8; 256];
+                    while let Ok(count) = stream.read(&mut buf) {
+                        if count == 0 || tx.send((index, buf[..count].to_vec())).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+        drop(tx);
+
+        let mut err_output = String::new();
+        // Return whether the line was progress.
+        let mut process_line = |line: &[u8]| {
+            let line = String::from_utf8_lossy(line);
+            if let Some((done, total)) = Self::parse_progress(&line) {
+                progress(Progress::new(phase, done, total));
+                return true;
+            } else if !line.trim().is_empty() {
+                err_output.push_str(&line);
+                err_output.push('\n');
+            }
+            false
+        };
+        // The partial line of each stream.
+        let mut lines: [Vec<u8>; 2] = [vec![], vec![]];
+        let mut watchdog = Watchdog::new(timeouts, phase, Instant::now());
+        loop {
+            let result = if cancel.is_cancelled() {
+                Err(Error::Cancelled)
+            } else {
+                watchdog.check(Instant::now())
+            };
+            if let Err(err) = result {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok((index, data)) => {
+                    let line = &mut lines[index];
+                    for c in data {
+                        // The status is updated with '\r'.
+                        if c == b'\r' || c == b'\n' {
+                            if process_line(line) {
+                                watchdog.progressed(Instant::now());
+                            }
+                            line.clear();
+                        } else {
+                            line.push(c);
+                        }
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        for line in &lines {
+            process_line(line);
+        }
+
+        let status = child.wait().map_err(Error::IOError)?;
+        if !status.success() {
+            return Err(Error::Failed(err_output));
+        }
+        Ok(())
+    }
+
+    /// Classify the error of running gpsbabel with the device.
+    fn classify(&self, err: Error) -> Error {
+        match err {
+            Error::Failed(output) => classify_output(&output, &self.port),
+            err => err,
+        }
+    }
+
+    /// Read from the device with the extra `option`, for its side
+    /// effect. Nothing is written, nor downloaded.
+    fn read_with_option(
+        &self,
+        option: &str,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        let info = probe()?;
+        let mut options = self.options.clone();
+        options.push(option.to_string());
+        let option_names: Vec<&str> = options.iter().map(String::as_str).collect();
+        info.check_read(&self.device_id, &option_names)?;
+        let command = GpsBabel::build_basic_command_line(
+            &info.path,
+            &self.device_id,
+            &options,
+            &self.port,
+            false,
+            false,
+        );
+        Self::run(command, Phase::Connecting, &timeouts(), progress, cancel)
+            .map_err(|err| self.classify(err))
+    }
+}
+
+/// Classify the error `output` of gpsbabel, for the device on `port`.
+fn classify_output(output: &str, port: &str) -> Error {
+    let lower = output.to_lowercase();
+    let has = |patterns: &[&str]| patterns.iter().any(|pattern| lower.contains(pattern));
+    if has(&["permission denied"]) {
+        Error::PermissionDenied(port.to_string())
+    } else if has(&["device or resource busy", "is locked"]) {
+        Error::PortBusy(port.to_string())
+    } else if has(&[
+        "timed out",
+        "timeout",
+        "no response",
+        "not responding",
+        "no data received",
+    ]) {
+        Error::NotResponding
+    } else if has(&[
+        "checksum",
+        "bad packet",
+        "unexpected",
+        "invalid response",
+        "protocol",
+    ]) {
+        let line = output
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default();
+        Error::Protocol(line.trim().to_string())
+    } else if output.trim().is_empty() {
+        Error::Failed("gpsbabel failed.".to_string())
+    } else {
+        Error::Failed(output.trim().to_string())
+    }
 }
 
 impl Driver for GpsBabel {
-    fn open(&mut self) -> bool {
-        !self.port.is_empty()
+    fn open(&mut self) -> Result<(), Error> {
+        if self.port.is_empty() {
+            return Err(Error::Failed("No port is set.".to_string()));
+        }
+        // gpsbabel opens the port itself. Check it can, without
+        // opening it.
+        let path = CString::new(self.port.as_bytes()).map_err(|_| Error::WrongArg)?;
+        if Path::new(&self.port).exists()
+            && unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) } != 0
+        {
+            return Err(Error::from_port_error(
+                io::Error::last_os_error(),
+                &self.port,
+            ));
+        }
+        Ok(())
     }
 
     fn close(&mut self) -> bool {
         true
     }
 
-    /// Download the data into a file. Return the PathBuf to said file on success.
-    /// Caller is responsible for deleting the file.
-    fn download(&self, format: Format, erase: bool) -> Result<PathBuf, Error> {
+    /// Download the data into a temporary file, deleted when dropped.
+    fn download(
+        &self,
+        format: Format,
+        erase: bool,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<TempFile, Error> {
         // we requested erase at the same time and it is not supported.
         if erase && !self.cap.can_erase {
             return Err(Error::Unsupported);
@@ -116,52 +833,366 @@ impl Driver for GpsBabel {
         }
         let extension = extension_opt.unwrap();
 
-        // XXX use a better temporary name
-        let mut dir = env::temp_dir();
-        dir.push(String::from("gpsami") + extension);
+        let info = probe()?;
+        let mut options: Vec<&str> = self.options.iter().map(String::as_str).collect();
+        if erase {
+            options.push("erase");
+        }
+        info.check_read(&self.device_id, &options)?;
+        info.check_write(fmt_string)?;
+
+        let temp = TempFile::new(&(String::from("gpsami") + extension)).map_err(Error::IOError)?;
 
         /* gpsbabel -t -w -i m241 -f /dev/ttyACM0 -o gpx -F $1 */
-        let output = GpsBabel::build_basic_command_line(&self.device_id, &self.port, erase, false)
+        let mut command = GpsBabel::build_basic_command_line(
+            &info.path,
+            &self.device_id,
+            &self.options,
+            &self.port,
+            erase,
+            false,
+        );
+        command
             .arg("-o")
             .arg(fmt_string) // format
             .arg("-F")
-            .arg(String::from(dir.to_str().unwrap()))
-            .output()
-            .expect("failed to execute process");
-        println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-        let err_output = String::from_utf8_lossy(&output.stderr);
-        if !output.status.success() {
-            return Err(Error::Failed(err_output.into_owned()));
+            .arg(temp.path());
+        Self::run(command, Phase::Downloading, &timeouts(), progress, cancel)
+            .map_err(|err| self.classify(err))?;
+        if !temp.path().exists() {
+            return Err(Error::Failed("gpsbabel didn't output any data".to_string()));
+        }
+        Ok(temp)
+    }
+
+    /// Start logging. gpsbabel reads the whole log before, and can't
+    /// stop logging.
+    fn set_logging(
+        &self,
+        enable: bool,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        if !enable || !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
         }
-        Ok(dir)
+        /* gpsbabel -t -w -i m241,log_enable -f /dev/ttyACM0 */
+        self.read_with_option("log_enable", progress, cancel)
+    }
+
+    /// Turn the device off. gpsbabel reads the whole log before.
+    fn shutoff(&self, progress: &ProgressFn, cancel: &CancelToken) -> Result<(), Error> {
+        if !self.cap.can_shutoff {
+            return Err(Error::Unsupported);
+        }
+        /* gpsbabel -t -w -i navilink,power_off -f /dev/ttyUSB0 */
+        self.read_with_option("power_off", progress, cancel)
     }
 
     /// Erase the logs on the device. Return an error if not capable.
-    fn erase(&self) -> Result<(), Error> {
+    fn erase(&self, progress: &ProgressFn, cancel: &CancelToken) -> Result<(), Error> {
         // Device doesn't support "erase only"
         if !self.cap.can_erase_only {
             return Err(Error::Unsupported);
         }
+        let info = probe()?;
+        let mut options: Vec<&str> = self.options.iter().map(String::as_str).collect();
+        options.push("erase_only");
+        info.check_read(&self.device_id, &options)?;
         /* gpsbabel -t -w -i m241,erase_only -f /dev/ttyACM0 */
-        let output = GpsBabel::build_basic_command_line(&self.device_id, &self.port, false, true)
-            .output()
-            .expect("failed to execute process");
-        println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-        let err_output = String::from_utf8_lossy(&output.stderr);
-        if !output.status.success() {
-            return Err(Error::Failed(err_output.into_owned()));
-        }
-        Ok(())
+        let command = GpsBabel::build_basic_command_line(
+            &info.path,
+            &self.device_id,
+            &self.options,
+            &self.port,
+            false,
+            true,
+        );
+        Self::run(command, Phase::Erasing, &timeouts(), progress, cancel)
+            .map_err(|err| self.classify(err))
     }
 }
 
 #[test]
 fn test_command_builder() {
-    let command = GpsBabel::build_basic_command_line("foo", "ttyS0", false, false);
+    let gpsbabel = Path::new("gpsbabel");
+    let command = GpsBabel::build_basic_command_line(gpsbabel, "foo", &[], "ttyS0", false, false);
     assert_eq!(
         format!("{:?}", command),
-        "\"gpsbabel\" \"-t\" \"-w\" \"-i\" \"foo\" \"-f\" \"ttyS0\""
-    )
+        "\"gpsbabel\" \"-vs\" \"-t\" \"-w\" \"-i\" \"foo\" \"-f\" \"ttyS0\""
+    );
+
+    let options = vec!["block_size_kb=1".to_string()];
+    let command =
+        GpsBabel::build_basic_command_line(gpsbabel, "foo", &options, "ttyS0", false, true);
+    assert_eq!(
+        format!("{:?}", command),
+        "\"gpsbabel\" \"-vs\" \"-t\" \"-w\" \"-i\" \"foo,block_size_kb=1,erase_only\" \"-f\" \"ttyS0\""
+    );
+}
+
+#[test]
+fn test_convert_command_line() {
+    let command = GpsBabel::build_convert_command_line(
+        Path::new("gpsbabel"),
+        Path::new("/tmp/in.gpx"),
+        "kml",
+        &[],
+        Path::new("/tmp/out.kml"),
+    );
+    assert_eq!(
+        format!("{:?}", command),
+        "\"gpsbabel\" \"-vs\" \"-t\" \"-w\" \"-i\" \"gpx\" \"-f\" \"/tmp/in.gpx\" \"-o\" \"kml\" \"-F\" \"/tmp/out.kml\""
+    );
+
+    let filters = [
+        Filter::TimeShift(-3600),
+        Filter::MaxHdop(5.0),
+        Filter::Merge,
+    ];
+    let command = GpsBabel::build_convert_command_line(
+        Path::new("gpsbabel"),
+        Path::new("/tmp/in.gpx"),
+        "gpx",
+        &filters,
+        Path::new("/tmp/out.gpx"),
+    );
+    assert_eq!(
+        format!("{:?}", command),
+        "\"gpsbabel\" \"-vs\" \"-t\" \"-w\" \"-i\" \"gpx\" \"-f\" \"/tmp/in.gpx\" \
+         \"-x\" \"track,move=-3600s\" \"-x\" \"discard,hdop=5\" \"-x\" \"track,merge\" \
+         \"-o\" \"gpx\" \"-F\" \"/tmp/out.gpx\""
+    );
+}
+
+#[test]
+fn test_version() {
+    assert_eq!(
+        Version::parse("\nGPSBabel Version 1.7.0\n\n"),
+        Some(Version(1, 7, 0))
+    );
+    assert_eq!(
+        Version::parse("GPSBabel Version 1.8.0-beta20220312"),
+        Some(Version(1, 8, 0))
+    );
+    assert_eq!(Version::parse("Version 2"), Some(Version(2, 0, 0)));
+    assert_eq!(Version::parse("foo 1.2.3"), None);
+    assert!(Version(1, 4, 4) < MIN_VERSION);
+    assert!(Version(1, 10, 0) > MIN_VERSION);
+    assert_eq!(Version(1, 7, 0).to_string(), "1.7.0");
+}
+
+#[test]
+fn test_capabilities() {
+    let output = "file\trwrwrw\tgpx\tgpx\tGPX XML\tgpx\t
+option\tgpx\tsnlen\tLength of generated shortnames\tinteger\t32\t1\t\t
+serial\t--r-r-\tm241\t\tHoluxM-241 (MTK based) download\tmtk_logger\t
+option\tm241\terase\tErase device data after download\tboolean\t0\t\t\t
+option\tm241\terase_only\tOnly erase device data, do not download anything\tboolean\t0\t\t\t
+file\t--rw--\tgarmin_fit\tfit\tFlexible and Interoperable Data Transfer (FIT) Protocol\tgarmin_fit\t
+";
+    let info = Info {
+        path: PathBuf::from("gpsbabel"),
+        version: Version(1, 7, 0),
+        formats: Info::parse_formats(output),
+    };
+    assert_eq!(info.formats.len(), 3);
+    assert_eq!(info.formats[1].name, "m241");
+    assert!(info.formats[1].read);
+    assert!(!info.formats[1].write);
+    assert_eq!(info.formats[1].options, vec!["erase", "erase_only"]);
+
+    assert!(info.check_read("m241", &["erase"]).is_ok());
+    assert!(info.check_read("m241", &["block_size_kb=1"]).is_err());
+    assert!(info.check_read("garmin_fit", &[]).is_ok());
+    assert!(info.check_read("garmin_fit", &["erase"]).is_err());
+    assert!(info.check_write("garmin_fit").is_ok());
+    assert!(info.check_write("m241").is_err());
+    assert!(info.check_read("bogus", &[]).is_err());
+
+    // Unknown formats aren't checked.
+    let info = Info {
+        formats: vec![],
+        ..info
+    };
+    assert!(info.check_read("bogus", &[]).is_ok());
+}
+
+#[test]
+fn test_find_in_path() {
+    let dir = TempFile::new("bin").unwrap();
+    fs::create_dir(dir.path()).unwrap();
+    let program = dir.path().join("gpsbabel");
+    fs::write(&program, "").unwrap();
+    let path_var = env::join_paths([Path::new("/nonexistent"), dir.path()]).unwrap();
+    // Not executable.
+    assert_eq!(find_in_path(Path::new("gpsbabel"), Some(&path_var)), None);
+
+    fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(
+        find_in_path(Path::new("gpsbabel"), Some(&path_var)),
+        Some(program.clone())
+    );
+    assert_eq!(find_in_path(&program, None), Some(program.clone()));
+    assert_eq!(find_in_path(Path::new("gpsbabel"), None), None);
+    assert_eq!(find_in_path(&dir.path().join("bogus"), None), None);
+}
+
+#[test]
+fn test_watchdog() {
+    let start = Instant::now();
+    let secs = Duration::from_secs;
+    let timeouts = Timeouts {
+        download: Some(secs(600)),
+        erase: None,
+        convert: None,
+        command: Some(secs(300)),
+        stall: Some(secs(60)),
+    };
+    let mut watchdog = Watchdog::new(&timeouts, Phase::Downloading, start);
+    // No stall before the first progress.
+    assert!(watchdog.check(start + secs(300)).is_ok());
+    watchdog.progressed(start + secs(300));
+    assert!(watchdog.check(start + secs(360)).is_ok());
+    match watchdog.check(start + secs(361)) {
+        Err(Error::Timeout(message)) => assert!(message.contains("60 seconds")),
+        _ => panic!("expected a stall"),
+    }
+    watchdog.progressed(start + secs(550));
+    assert!(watchdog.check(start + secs(600)).is_ok());
+    match watchdog.check(start + secs(601)) {
+        Err(Error::Timeout(message)) => {
+            assert_eq!(message, "Downloading took longer than 600 seconds.")
+        }
+        _ => panic!("expected a timeout"),
+    }
+
+    // The commands to the device stall too.
+    let mut watchdog = Watchdog::new(&timeouts, Phase::Connecting, start);
+    assert!(watchdog.check(start + secs(120)).is_ok());
+    watchdog.progressed(start + secs(120));
+    assert!(watchdog.check(start + secs(181)).is_err());
+    watchdog.progressed(start + secs(240));
+    match watchdog.check(start + secs(301)) {
+        Err(Error::Timeout(message)) => {
+            assert_eq!(message, "Connecting took longer than 300 seconds.")
+        }
+        _ => panic!("expected a timeout"),
+    }
+
+    // Erasing doesn't stall.
+    let mut watchdog = Watchdog::new(&timeouts, Phase::Erasing, start);
+    watchdog.progressed(start);
+    assert!(watchdog.check(start + secs(3600)).is_ok());
+}
+
+#[test]
+fn test_run_timeout() {
+    let timeouts = Timeouts {
+        convert: Some(Duration::from_millis(200)),
+        ..Timeouts::DEFAULT
+    };
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("sleep 10");
+    let start = Instant::now();
+    let result = GpsBabel::run(
+        command,
+        Phase::Writing,
+        &timeouts,
+        &|_| {},
+        &CancelToken::new(),
+    );
+    assert!(matches!(result, Err(Error::Timeout(_))));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_run_progress() {
+    // The status gpsbabel -vs outputs, and an error.
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(
+        "printf '1/4/25\\r2/4/50\\r4/4/100\\r'; \
+         echo 'mtk_logger: Unexpected data' >&2; exit 1",
+    );
+    let progress = Arc::new(Mutex::new(vec![]));
+    let progress2 = progress.clone();
+    let result = GpsBabel::run(
+        command,
+        Phase::Downloading,
+        &Timeouts::DEFAULT,
+        &move |p: Progress| progress2.lock().unwrap().push((p.phase, p.done, p.total)),
+        &CancelToken::new(),
+    );
+    match result {
+        Err(Error::Failed(output)) => assert_eq!(output, "mtk_logger: Unexpected data\n"),
+        _ => panic!("expected a failure"),
+    }
+    assert_eq!(
+        *progress.lock().unwrap(),
+        vec![
+            (Phase::Connecting, 0, None),
+            (Phase::Downloading, 1, Some(4)),
+            (Phase::Downloading, 2, Some(4)),
+            (Phase::Downloading, 4, Some(4)),
+        ]
+    );
+}
+
+#[test]
+fn test_classify_output() {
+    let port = "/dev/ttyUSB0";
+    assert!(matches!(
+        classify_output("mtk_logger: Can't open port '/dev/ttyUSB0': Permission denied\n", port),
+        Error::PermissionDenied(ref p) if p == port
+    ));
+    assert!(matches!(
+        classify_output("Device or resource busy", port),
+        Error::PortBusy(_)
+    ));
+    assert!(matches!(
+        classify_output("mtk_logger: Timeout waiting for response\n", port),
+        Error::NotResponding
+    ));
+    match classify_output("\nGarmin: Bad checksum\nmore\n", port) {
+        Error::Protocol(message) => assert_eq!(message, "Garmin: Bad checksum"),
+        _ => panic!("expected a protocol error"),
+    }
+    match classify_output("  something else \n", port) {
+        Error::Failed(message) => assert_eq!(message, "something else"),
+        _ => panic!("expected a failure"),
+    }
+    assert_eq!(classify_output("", port).to_string(), "gpsbabel failed.");
+}
+
+#[test]
+fn test_filters() {
+    assert_eq!(
+        Filter::parse("simplify=500"),
+        Some(Filter::SimplifyCount(500))
+    );
+    assert_eq!(
+        Filter::parse("simplify=10m"),
+        Some(Filter::SimplifyError(10.0))
+    );
+    assert_eq!(Filter::parse(" shift=+60 "), Some(Filter::TimeShift(60)));
+    assert_eq!(Filter::parse("dedupe"), Some(Filter::Dedupe));
+    assert_eq!(Filter::parse("hdop=2.5"), Some(Filter::MaxHdop(2.5)));
+    assert_eq!(Filter::parse("simplify"), None);
+    assert_eq!(Filter::parse("simplify=0"), None);
+    assert_eq!(Filter::parse("merge=1"), None);
+    assert_eq!(Filter::parse("bogus"), None);
+
+    assert_eq!(
+        Filter::parse_list("merge;;simplify=10m", ';'),
+        Ok(vec![Filter::Merge, Filter::SimplifyError(10.0)])
+    );
+    assert_eq!(Filter::parse_list("", ','), Ok(vec![]));
+    assert!(Filter::parse_list("merge,hdop=x", ',').is_err());
+
+    assert_eq!(Filter::SimplifyCount(500).to_arg(), "simplify,count=500");
+    assert_eq!(Filter::SimplifyError(10.0).to_arg(), "simplify,error=0.01k");
+    assert_eq!(Filter::TimeShift(60).to_arg(), "track,move=+60s");
+    assert_eq!(Filter::Dedupe.to_arg(), "duplicate,location");
 }
 
 #[test]
@@ -172,6 +1203,13 @@ fn test_format() {
 
     let result = GpsBabel::format_to_string(&Format::None);
     assert!(result.is_none());
+
+    for format in &Format::ALL {
+        assert!(GpsBabel::format_to_string(format).is_some());
+        assert!(GpsBabel::format_to_extension(format).is_some());
+    }
+    assert_eq!(GpsBabel::format_to_string(&Format::Tcx), Some("gtrnctr"));
+    assert_eq!(GpsBabel::format_to_string(&Format::Csv), Some("unicsv"));
 }
 
 #[test]
@@ -183,3 +1221,20 @@ fn test_extensions() {
     let result = GpsBabel::format_to_string(&Format::None);
     assert!(result.is_none());
 }
+
+#[test]
+fn test_parse_progress() {
+    assert_eq!(GpsBabel::parse_progress(" 42%"), Some((42, Some(100))));
+    assert_eq!(GpsBabel::parse_progress("Reading 7%"), Some((7, Some(100))));
+    assert_eq!(
+        GpsBabel::parse_progress("120/4000"),
+        Some((120, Some(4000)))
+    );
+    assert_eq!(GpsBabel::parse_progress("1/2/50"), Some((1, Some(2))));
+    assert_eq!(GpsBabel::parse_progress("1/2/x"), None);
+    assert_eq!(GpsBabel::parse_progress("1/2/3/4"), None);
+    assert_eq!(
+        GpsBabel::parse_progress("mtk_logger: Can't open port"),
+        None
+    );
+}
@@ -0,0 +1,609 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! GPX 1.0 and 1.1 reader and writer.
+//!
+//! The XML parser only handles what is needed for GPX: elements,
+//! attributes, text, CDATA and the predefined entities. Namespace
+//! prefixes are ignored. Routes aren't supported.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::track::{Document, Point, Segment, Time, Track};
+
+/// GPX version to write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    /// GPX 1.0, that has the speed and course.
+    Gpx10,
+    #[allow(dead_code)]
+    Gpx11,
+}
+
+/// Error reading GPX.
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// An XML element.
+#[derive(Debug, Default)]
+struct Element {
+    /// The name without the namespace prefix.
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+    /// The line of the start tag.
+    line: usize,
+}
+
+impl Element {
+    fn error(&self, message: String) -> Error {
+        Error {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Find the first descendant element `name`.
+    fn find(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|child| {
+            if child.name == name {
+                Some(child)
+            } else {
+                child.find(name)
+            }
+        })
+    }
+
+    /// The text of the child `name`, trimmed.
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.trim())
+    }
+}
+
+/// Strip the namespace prefix of `name`.
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Replace the entities and character references in `s`.
+fn unescape(s: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| "Unterminated entity".to_string())?
+            + start;
+        let entity = &rest[start + 1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = entity.strip_prefix('#') {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(std::char::from_u32)
+                    .ok_or_else(|| format!("Unknown entity &{};", entity))?
+            }
+        };
+        result.push(c);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Escape the text for XML.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// The current line.
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        // Skip the BOM.
+        let pos = if input.starts_with('\u{feff}') { 3 } else { 0 };
+        Parser {
+            input,
+            pos,
+            line: 1,
+        }
+    }
+
+    /// Move forward by `len` bytes.
+    fn advance(&mut self, len: usize) {
+        self.line += self.input[self.pos..self.pos + len].matches('\n').count();
+        self.pos += len;
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error {
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), Error> {
+        if !self.starts_with(s) {
+            return Err(self.error(&format!("Expected '{}'", s)));
+        }
+        self.advance(s.len());
+        Ok(())
+    }
+
+    /// Return what is before `end` and move after it.
+    fn take_until(&mut self, end: &str) -> Result<&'a str, Error> {
+        match self.rest().find(end) {
+            Some(idx) => {
+                let taken = &self.rest()[..idx];
+                self.advance(idx + end.len());
+                Ok(taken)
+            }
+            None => Err(self.error(&format!("Missing '{}'", end))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.advance(rest.len() - rest.trim_start().len());
+    }
+
+    /// Skip the whitespace, comments, processing instructions and
+    /// doctype that can be outside the root element.
+    fn skip_misc(&mut self) -> Result<(), Error> {
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("<!--") {
+                self.take_until("-->")?;
+            } else if self.starts_with("<?") {
+                self.take_until("?>")?;
+            } else if self.starts_with("<!DOCTYPE") {
+                self.take_until(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<&'a str, Error> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>' || c == '=')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("Expected a name"));
+        }
+        self.advance(len);
+        Ok(&rest[..len])
+    }
+
+    fn parse_element(&mut self) -> Result<Element, Error> {
+        let line = self.line;
+        self.expect("<")?;
+        let qname = self.parse_name()?;
+        let mut element = Element {
+            name: local_name(qname).to_string(),
+            line,
+            ..Element::default()
+        };
+
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("/>") {
+                self.advance(2);
+                return Ok(element);
+            }
+            if self.starts_with(">") {
+                self.advance(1);
+                break;
+            }
+            let name = self.parse_name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.starts_with("\"") { "\"" } else { "'" };
+            self.expect(quote)?;
+            let value = self.take_until(quote)?;
+            let value = unescape(value).map_err(|e| self.error(&e))?;
+            element
+                .attributes
+                .push((local_name(name).to_string(), value));
+        }
+
+        loop {
+            if self.starts_with("</") {
+                self.advance(2);
+                if self.parse_name()? != qname {
+                    return Err(self.error(&format!("Expected the end of <{}>", qname)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.starts_with("<!--") {
+                self.take_until("-->")?;
+            } else if self.starts_with("<![CDATA[") {
+                self.advance(9);
+                let text = self.take_until("]]>")?;
+                element.text.push_str(text);
+            } else if self.starts_with("<?") {
+                self.take_until("?>")?;
+            } else if self.starts_with("<") {
+                let child = self.parse_element()?;
+                element.children.push(child);
+            } else if self.rest().is_empty() {
+                return Err(self.error(&format!("Unexpected end in <{}>", qname)));
+            } else {
+                let len = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = &self.rest()[..len];
+                let text = unescape(text).map_err(|e| self.error(&e))?;
+                element.text.push_str(&text);
+                self.advance(len);
+            }
+        }
+    }
+
+    /// Parse the document and return the root element.
+    fn parse_document(&mut self) -> Result<Element, Error> {
+        self.skip_misc()?;
+        let root = self.parse_element()?;
+        self.skip_misc()?;
+        if !self.rest().is_empty() {
+            return Err(self.error("Content after the root element"));
+        }
+        Ok(root)
+    }
+}
+
+/// Parse the text of `element`.
+fn parse_text<T: FromStr>(element: &Element) -> Result<T, Error> {
+    element
+        .text
+        .trim()
+        .parse()
+        .map_err(|_| element.error(format!("Invalid <{}>", element.name)))
+}
+
+/// Parse the text of the child `name` if it exists.
+fn parse_child<T: FromStr>(element: &Element, name: &str) -> Result<Option<T>, Error> {
+    element.child(name).map(parse_text).transpose()
+}
+
+/// Parse the coordinate attribute `name`, between -`max` and `max`.
+fn parse_coordinate(element: &Element, name: &str, max: f64) -> Result<f64, Error> {
+    let value = element
+        .attribute(name)
+        .ok_or_else(|| element.error(format!("Missing {}", name)))?;
+    match value.trim().parse::<f64>() {
+        Ok(value) if value >= -max && value <= max => Ok(value),
+        _ => Err(element.error(format!("Invalid {} {}", name, value))),
+    }
+}
+
+fn read_point(element: &Element) -> Result<Point, Error> {
+    let mut point = Point::new(
+        parse_coordinate(element, "lat", 90.0)?,
+        parse_coordinate(element, "lon", 180.0)?,
+    );
+    point.ele = parse_child(element, "ele")?;
+    if let Some(time) = element.child("time") {
+        point.time =
+            Some(Time::parse(&time.text).ok_or_else(|| time.error("Invalid <time>".to_string()))?);
+    }
+    point.course = parse_child(element, "course")?;
+    point.speed = parse_child(element, "speed")?;
+    // GPX 1.1 has the speed and course in the extensions.
+    if let Some(extensions) = element.child("extensions") {
+        if let Some(speed) = extensions.find("speed") {
+            point.speed = Some(parse_text(speed)?);
+        }
+        if let Some(course) = extensions.find("course") {
+            point.course = Some(parse_text(course)?);
+        }
+    }
+    point.sat = parse_child(element, "sat")?;
+    point.hdop = parse_child(element, "hdop")?;
+    point.vdop = parse_child(element, "vdop")?;
+    point.pdop = parse_child(element, "pdop")?;
+    point.name = element.child_text("name").map(str::to_string);
+    Ok(point)
+}
+
+/// Read the GPX document in `content`.
+pub fn read(content: &str) -> Result<Document, Error> {
+    let root = Parser::new(content).parse_document()?;
+    if root.name != "gpx" {
+        return Err(root.error(format!("<{}> is not GPX", root.name)));
+    }
+
+    let mut document = Document::default();
+    for wpt in root.children("wpt") {
+        document.waypoints.push(read_point(wpt)?);
+    }
+    for trk in root.children("trk") {
+        let mut track = Track {
+            name: trk.child_text("name").map(str::to_string),
+            segments: vec![],
+        };
+        for trkseg in trk.children("trkseg") {
+            let points = trkseg
+                .children("trkpt")
+                .map(read_point)
+                .collect::<Result<Vec<Point>, Error>>()?;
+            track.segments.push(Segment { points });
+        }
+        document.tracks.push(track);
+    }
+    Ok(document)
+}
+
+fn write_point<W: Write>(
+    out: &mut W,
+    tag: &str,
+    point: &Point,
+    version: Version,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "<{} lat=\"{:.9}\" lon=\"{:.9}\">",
+        tag, point.lat, point.lon
+    )?;
+    if let Some(ele) = point.ele {
+        writeln!(out, "  <ele>{:.3}</ele>", ele)?;
+    }
+    if let Some(time) = point.time {
+        writeln!(out, "  <time>{}</time>", time)?;
+    }
+    // Only GPX 1.0 has the course and speed.
+    if version == Version::Gpx10 {
+        if let Some(course) = point.course {
+            writeln!(out, "  <course>{:.6}</course>", course)?;
+        }
+        if let Some(speed) = point.speed {
+            writeln!(out, "  <speed>{:.6}</speed>", speed)?;
+        }
+    }
+    if let Some(ref name) = point.name {
+        writeln!(out, "  <name>{}</name>", escape(name))?;
+    }
+    if let Some(sat) = point.sat {
+        writeln!(out, "  <sat>{}</sat>", sat)?;
+    }
+    if let Some(hdop) = point.hdop {
+        writeln!(out, "  <hdop>{:.2}</hdop>", hdop)?;
+    }
+    if let Some(vdop) = point.vdop {
+        writeln!(out, "  <vdop>{:.2}</vdop>", vdop)?;
+    }
+    if let Some(pdop) = point.pdop {
+        writeln!(out, "  <pdop>{:.2}</pdop>", pdop)?;
+    }
+    writeln!(out, "</{}>", tag)
+}
+
+/// Write the `document` as GPX `version`.
+pub fn write<W: Write>(out: &mut W, document: &Document, version: Version) -> io::Result<()> {
+    let (version_string, namespace) = match version {
+        Version::Gpx10 => ("1.0", "http://www.topografix.com/GPX/1/0"),
+        Version::Gpx11 => ("1.1", "http://www.topografix.com/GPX/1/1"),
+    };
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<gpx version=\"{}\" creator=\"gpsami\" xmlns=\"{}\">",
+        version_string, namespace
+    )?;
+    for point in &document.waypoints {
+        write_point(out, "wpt", point, version)?;
+    }
+    for track in &document.tracks {
+        writeln!(out, "<trk>")?;
+        if let Some(ref name) = track.name {
+            writeln!(out, "<name>{}</name>", escape(name))?;
+        }
+        for segment in &track.segments {
+            writeln!(out, "<trkseg>")?;
+            for point in &segment.points {
+                write_point(out, "trkpt", point, version)?;
+            }
+            writeln!(out, "</trkseg>")?;
+        }
+        writeln!(out, "</trk>")?;
+    }
+    writeln!(out, "</gpx>")
+}
+
+#[test]
+fn test_unescape() {
+    assert_eq!(
+        unescape("a &lt;b&gt; &amp; &#65;&#x42;"),
+        Ok("a <b> & AB".to_string())
+    );
+    assert!(unescape("&bogus;").is_err());
+    assert!(unescape("&amp").is_err());
+    assert_eq!(escape("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+}
+
+#[test]
+fn test_read_gpx10() {
+    let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Downloaded -->
+<gpx version="1.0" creator="GPSBabel - http://www.gpsbabel.org" xmlns="http://www.topografix.com/GPX/1/0">
+<time>2019-05-04T14:00:00Z</time>
+<wpt lat="45.500000000" lon="-73.500000000">
+  <ele>30.5</ele>
+  <time>2019-05-04T12:05:00Z</time>
+  <name>Caf&#233; &amp; bar</name>
+</wpt>
+<trk>
+<name><![CDATA[Track <1>]]></name>
+<trkseg>
+<trkpt lat='45.5' lon='-73.5'>
+  <ele>31.000</ele>
+  <time>2019-05-04T12:00:00.500Z</time>
+  <course>90.0</course>
+  <speed>1.5</speed>
+  <sat>7</sat>
+  <hdop>0.90</hdop>
+</trkpt>
+<trkpt lat="45.6" lon="-73.6"/>
+</trkseg>
+<trkseg>
+</trkseg>
+</trk>
+</gpx>
+"#;
+    let document = read(content).unwrap();
+    assert_eq!(document.waypoints.len(), 1);
+    let waypoint = &document.waypoints[0];
+    assert_eq!(waypoint.name.as_deref(), Some("Café & bar"));
+    assert_eq!(waypoint.ele, Some(30.5));
+    assert_eq!(document.tracks.len(), 1);
+    let track = &document.tracks[0];
+    assert_eq!(track.name.as_deref(), Some("Track <1>"));
+    assert_eq!(track.segments.len(), 2);
+    let point = &track.segments[0].points[0];
+    assert_eq!(point.lat, 45.5);
+    assert_eq!(point.lon, -73.5);
+    assert_eq!(point.time, Some(Time::from_unix(1_556_971_200, 500)));
+    assert_eq!(point.course, Some(90.0));
+    assert_eq!(point.speed, Some(1.5));
+    assert_eq!(point.sat, Some(7));
+    assert_eq!(point.hdop, Some(0.9));
+    assert_eq!(track.segments[0].points[1], Point::new(45.6, -73.6));
+    assert_eq!(document.point_count(), 3);
+}
+
+#[test]
+fn test_read_gpx11() {
+    let content = r#"<?xml version="1.0"?>
+<gpx:gpx version="1.1" xmlns:gpx="http://www.topografix.com/GPX/1/1"
+  xmlns:tpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2">
+<gpx:metadata><gpx:time>2019-05-04T14:00:00Z</gpx:time></gpx:metadata>
+<gpx:trk><gpx:trkseg>
+<gpx:trkpt lat="45.5" lon="-73.5">
+  <gpx:time>2019-05-04T08:00:00-04:00</gpx:time>
+  <gpx:extensions><tpx:TrackPointExtension>
+    <tpx:speed>2.5</tpx:speed>
+  </tpx:TrackPointExtension></gpx:extensions>
+</gpx:trkpt>
+</gpx:trkseg></gpx:trk>
+</gpx:gpx>"#;
+    let document = read(content).unwrap();
+    let point = &document.tracks[0].segments[0].points[0];
+    assert_eq!(point.time, Time::parse("2019-05-04T12:00:00Z"));
+    assert_eq!(point.speed, Some(2.5));
+}
+
+#[test]
+fn test_read_errors() {
+    assert_eq!(read("").unwrap_err().message, "Expected '<'");
+    assert_eq!(read("<kml></kml>").unwrap_err().message, "<kml> is not GPX");
+    assert_eq!(read("<gpx>\n<trk>\n</gpx>").unwrap_err().line, 3);
+    assert!(read("<gpx><wpt lat=\"91\" lon=\"0\"/></gpx>").is_err());
+    assert!(read("<gpx><wpt lon=\"0\"/></gpx>").is_err());
+    assert!(read("<gpx><wpt lat=\"1\" lon=\"0\"><ele>x</ele></wpt></gpx>").is_err());
+    assert!(read("<gpx><wpt lat=\"1\" lon=\"0\"><time>x</time></wpt></gpx>").is_err());
+    assert!(read("<gpx></gpx><gpx></gpx>").is_err());
+    assert!(read("<gpx>").is_err());
+}
+
+#[test]
+fn test_write() {
+    let mut point = Point::new(45.5, -73.5);
+    point.ele = Some(31.0);
+    point.time = Some(Time::from_unix(1_556_971_200, 500));
+    point.speed = Some(1.5);
+    point.course = Some(90.0);
+    point.hdop = Some(0.9);
+    point.sat = Some(7);
+    let mut waypoint = Point::new(45.6, -73.6);
+    waypoint.name = Some("A & B".to_string());
+    let document = Document {
+        tracks: vec![Track {
+            name: Some("Track".to_string()),
+            segments: vec![Segment {
+                points: vec![point.clone()],
+            }],
+        }],
+        waypoints: vec![waypoint],
+    };
+
+    let mut out = vec![];
+    write(&mut out, &document, Version::Gpx10).unwrap();
+    let output = String::from_utf8(out).unwrap();
+    assert!(output.contains("xmlns=\"http://www.topografix.com/GPX/1/0\""));
+    assert!(output.contains("<name>A &amp; B</name>"));
+    assert_eq!(read(&output).unwrap(), document);
+
+    let mut out = vec![];
+    write(&mut out, &document, Version::Gpx11).unwrap();
+    let output = String::from_utf8(out).unwrap();
+    assert!(output.contains("version=\"1.1\""));
+    assert!(!output.contains("<speed>"));
+    let read_back = read(&output).unwrap();
+    assert_eq!(read_back.tracks[0].segments[0].points[0].speed, None);
+    assert_eq!(read_back.waypoints, document.waypoints);
+}
@@ -0,0 +1,169 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json;
+
+use crate::gpx;
+use crate::tempfile::TempFile;
+use crate::track::Document;
+use crate::Format;
+
+/// A download recorded in the library.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Entry {
+    /// The device model id.
+    pub model: String,
+    pub port: String,
+    /// When it was downloaded, in seconds since the epoch.
+    pub timestamp: u64,
+    pub output: PathBuf,
+    /// The format id. None is GPX, from before it was recorded.
+    pub format: Option<String>,
+    /// Number of points (track points and waypoints).
+    pub points: u64,
+    /// Time of the first and last points.
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    /// SHA-256 of the output file.
+    pub checksum: String,
+}
+
+/// Compute the checksum of `data`.
+pub fn checksum(data: &[u8]) -> String {
+    glib::compute_checksum_for_data(glib::ChecksumType::Sha256, data)
+        .map(|checksum| checksum.to_string())
+        .unwrap_or_default()
+}
+
+impl Entry {
+    /// Create the entry for the file `output` just downloaded
+    /// from `model` on `port`. The points are only known for GPX.
+    pub fn from_file(model: &str, port: &str, format: Format, output: &Path) -> io::Result<Entry> {
+        let data = fs::read(output)?;
+        let document = match format {
+            Format::Gpx => gpx::read(&String::from_utf8_lossy(&data)).unwrap_or_else(|err| {
+                eprintln!("Can't read {:?}: {}", output, err);
+                Document::default()
+            }),
+            _ => Document::default(),
+        };
+        let time_span = document.time_span();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Entry {
+            model: model.to_string(),
+            port: port.to_string(),
+            timestamp,
+            output: output.to_owned(),
+            format: Some(format.id().to_string()),
+            points: document.point_count() as u64,
+            start_time: time_span.map(|(start, _)| start.to_string()),
+            end_time: time_span.map(|(_, end)| end.to_string()),
+            checksum: checksum(&data),
+        })
+    }
+}
+
+/// The library of downloads, stored as JSON.
+pub struct Library {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Library {
+    /// The default location of the library.
+    pub fn default_path() -> PathBuf {
+        // Like the settings. We treat a failure of this as fatal.
+        let mut path: PathBuf = dirs::home_dir().expect("Can't locate home_dir");
+        path.push(".gpsami");
+        path.push("library.json");
+        path
+    }
+
+    /// Load the library from `path`. A missing file is an empty library.
+    pub fn load(path: &Path) -> io::Result<Library> {
+        let entries = match fs::read_to_string(path) {
+            Ok(content) => json::decode(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        Ok(Library {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    /// The entries, oldest first.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Add `entry` and save the library.
+    pub fn add(&mut self, entry: Entry) -> io::Result<()> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = TempFile::new("library.json")?;
+        fs::write(temp.path(), json::as_pretty_json(&self.entries).to_string())?;
+        temp.persist(&self.path)
+    }
+}
+
+#[test]
+fn test_library() {
+    let dir = TempFile::new("library").unwrap();
+    let path = dir.path().join("library.json");
+    let mut library = Library::load(&path).unwrap();
+    assert!(library.entries().is_empty());
+
+    let entry = Entry {
+        model: "m241".to_string(),
+        port: "/dev/ttyUSB0".to_string(),
+        timestamp: 1_556_971_200,
+        output: PathBuf::from("/tmp/out.gpx"),
+        format: Some("gpx".to_string()),
+        points: 3,
+        start_time: Some("2019-05-04T12:00:00Z".to_string()),
+        end_time: None,
+        checksum: "0000".to_string(),
+    };
+    library.add(entry.clone()).unwrap();
+
+    let library = Library::load(&path).unwrap();
+    assert_eq!(library.entries(), std::slice::from_ref(&entry));
+
+    // Entries from before the format was recorded.
+    let mut old = json::Json::from_str(&json::encode(&[entry]).unwrap()).unwrap();
+    old.as_array_mut().unwrap()[0]
+        .as_object_mut()
+        .unwrap()
+        .remove("format");
+    fs::write(&path, old.to_string()).unwrap();
+    assert_eq!(Library::load(&path).unwrap().entries()[0].format, None);
+
+    fs::write(&path, "garbage").unwrap();
+    assert!(Library::load(&path).is_err());
+}
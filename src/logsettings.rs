@@ -0,0 +1,131 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The logging settings of a device.
+
+/// The fields a device can record for each point, by id and label.
+pub const FIELDS: &[(&str, &str)] = &[
+    ("utc", "Time"),
+    ("valid", "Fix type"),
+    ("latitude", "Latitude"),
+    ("longitude", "Longitude"),
+    ("height", "Height"),
+    ("speed", "Speed"),
+    ("heading", "Heading"),
+    ("dsta", "DGPS station"),
+    ("dage", "DGPS age"),
+    ("pdop", "PDOP"),
+    ("hdop", "HDOP"),
+    ("vdop", "VDOP"),
+    ("nsat", "Satellites"),
+    ("sid", "Satellite ids"),
+    ("elevation", "Satellite elevation"),
+    ("azimuth", "Satellite azimuth"),
+    ("snr", "Satellite SNR"),
+    ("rcr", "Reason"),
+    ("millisecond", "Milliseconds"),
+    ("distance", "Distance"),
+];
+
+/// What the device does when its memory is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhenFull {
+    /// Overwrite the oldest points.
+    Overwrite,
+    /// Stop logging.
+    Stop,
+}
+
+impl WhenFull {
+    pub fn id(self) -> &'static str {
+        match self {
+            WhenFull::Overwrite => "overwrite",
+            WhenFull::Stop => "stop",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<WhenFull> {
+        match id {
+            "overwrite" => Some(WhenFull::Overwrite),
+            "stop" => Some(WhenFull::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// When and what the device logs. A point is logged when any of the
+/// criteria is met. 0 disables a criterion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogSettings {
+    /// Log every this many seconds.
+    pub time_interval: f64,
+    /// Log every this many meters.
+    pub distance_interval: f64,
+    /// Log above this speed, in km/h.
+    pub speed_threshold: f64,
+    /// The ids of the fields recorded, from `FIELDS`.
+    pub fields: Vec<String>,
+    pub when_full: WhenFull,
+}
+
+/// Parse a criterion, a positive number or 0.
+pub fn parse_amount(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(amount) if amount.is_finite() && amount >= 0.0 => Ok(amount),
+        _ => Err(format!("Invalid amount: {}", value)),
+    }
+}
+
+/// Parse the field ids separated by commas.
+pub fn parse_fields(list: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        if !FIELDS.iter().any(|(field, _)| *field == id) {
+            return Err(format!("Unknown field: {}", id));
+        }
+        if !fields.iter().any(|field| field == id) {
+            fields.push(id.to_string());
+        }
+    }
+    if fields.is_empty() {
+        return Err("No field to record".to_string());
+    }
+    Ok(fields)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse_amount("0"), Ok(0.0));
+    assert_eq!(parse_amount(" 2.5"), Ok(2.5));
+    assert!(parse_amount("-1").is_err());
+    assert!(parse_amount("inf").is_err());
+    assert!(parse_amount("often").is_err());
+
+    assert_eq!(
+        parse_fields("utc, latitude,longitude,utc"),
+        Ok(vec![
+            "utc".to_string(),
+            "latitude".to_string(),
+            "longitude".to_string()
+        ])
+    );
+    assert!(parse_fields("utc,altitude").is_err());
+    assert!(parse_fields(" ,").is_err());
+
+    assert_eq!(WhenFull::from_id("stop"), Some(WhenFull::Stop));
+    assert_eq!(
+        WhenFull::from_id(WhenFull::Overwrite.id()),
+        Some(WhenFull::Overwrite)
+    );
+    assert_eq!(WhenFull::from_id("wrap"), None);
+}
@@ -17,6 +17,7 @@ extern crate glib;
 extern crate glib_sys;
 extern crate gtk;
 extern crate gudev;
+extern crate libc;
 extern crate libudev;
 extern crate rustc_serialize;
 
@@ -25,18 +26,91 @@ use gio::prelude::*;
 
 use mgapplication::MgApplication;
 
+mod cli;
 mod config;
 mod devices;
 mod drivers;
 mod gpsbabel;
+mod gpx;
+mod library;
+mod logsettings;
 mod mgapplication;
+mod mtk;
+mod portaccess;
+mod serial;
 mod static_resources;
+mod split;
+mod stats;
+mod tempfile;
+mod template;
+mod track;
 mod utils;
+mod verify;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Format {
     None,
     Gpx,
     Kml,
+    GeoJson,
+    /// Garmin Training Center
+    Tcx,
+    /// gpsbabel unicsv
+    Csv,
+    Nmea,
+    /// OziExplorer
+    Ozi,
+    /// Garmin FIT, if gpsbabel can write it.
+    Fit,
+}
+
+impl Format {
+    /// All the output formats.
+    pub const ALL: [Format; 8] = [
+        Format::Gpx,
+        Format::Kml,
+        Format::GeoJson,
+        Format::Tcx,
+        Format::Csv,
+        Format::Nmea,
+        Format::Ozi,
+        Format::Fit,
+    ];
+
+    /// The id, for the settings and the command line.
+    pub fn id(self) -> &'static str {
+        match self {
+            Format::None => "none",
+            Format::Gpx => "gpx",
+            Format::Kml => "kml",
+            Format::GeoJson => "geojson",
+            Format::Tcx => "tcx",
+            Format::Csv => "csv",
+            Format::Nmea => "nmea",
+            Format::Ozi => "ozi",
+            Format::Fit => "fit",
+        }
+    }
+
+    /// The format for `id`.
+    pub fn from_id(id: &str) -> Option<Format> {
+        Format::ALL.iter().cloned().find(|format| format.id() == id)
+    }
+
+    /// The label for the UI.
+    pub fn label(self) -> &'static str {
+        match self {
+            Format::None => "None",
+            Format::Gpx => "GPX",
+            Format::Kml => "KML (Google Earth)",
+            Format::GeoJson => "GeoJSON",
+            Format::Tcx => "TCX (Garmin Training Center)",
+            Format::Csv => "CSV",
+            Format::Nmea => "NMEA",
+            Format::Ozi => "OziExplorer",
+            Format::Fit => "FIT (Garmin)",
+        }
+    }
 }
 
 /// Init Gtk and stuff.
@@ -62,6 +136,12 @@ fn init() {
 }
 
 fn main() {
+    // Headless commands don't need Gtk.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
+
     init();
 
     let gapp = gtk::Application::new(
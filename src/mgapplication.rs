@@ -20,10 +20,21 @@ use std::path;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::devices;
 use crate::drivers;
+use crate::gpsbabel;
+use crate::gpx;
+use crate::library;
+use crate::logsettings::{self, LogSettings, WhenFull};
+use crate::split;
+use crate::stats;
+use crate::tempfile::TempFile;
+use crate::template;
+use crate::track;
 use crate::utils;
+use crate::verify;
 use crate::Format;
 
 enum UIState {
@@ -33,13 +44,51 @@ enum UIState {
 
 pub enum MgAction {
     RescanDevices,
+    DeviceAdded(String),
     ModelChanged(String),
     PortChanged(String),
     StartErase,
-    DoneErase(drivers::Error),
+    DoneErase(Result<(), drivers::Error>),
+    SetLogging(bool),
+    DoneSetLogging(Result<bool, drivers::Error>),
+    StartShutoff,
+    DoneShutoff(Result<(), drivers::Error>),
+    ReadLogSettings,
+    WriteLogSettings,
+    DoneLogSettings(Result<LogSettings, drivers::Error>),
     StartDownload,
-    DoneDownload(drivers::Error),
+    DoneDownload(Result<(), drivers::Error>),
+    Progress(drivers::Progress),
+    CancelOperation,
     SetOutputDir(path::PathBuf),
+    SetAutoDownload(bool),
+    SetVerifyErase(bool),
+    SetShutoff(bool),
+    SetSplitMode(String),
+    SetTemplate(String),
+    SetFormats(Vec<Format>),
+    AddToLibrary(library::Entry),
+    OpenTrack,
+    ExportTrack,
+    HistorySelectionChanged,
+}
+
+// The columns of the history store.
+const HISTORY_COL_DATE: u32 = 0;
+const HISTORY_COL_MODEL: u32 = 1;
+const HISTORY_COL_POINTS: u32 = 2;
+const HISTORY_COL_FILE: u32 = 3;
+const HISTORY_COL_PATH: u32 = 4;
+const HISTORY_COL_INDEX: u32 = 5;
+
+/// Where to save a download.
+enum Destination {
+    /// The file chosen.
+    File(path::PathBuf),
+    /// The directory, with the file named from the template.
+    Named(path::PathBuf, template::Template),
+    /// The directory, with the download split into files.
+    Split(path::PathBuf, split::Splitter),
 }
 
 fn post_event(sender: &glib::Sender<MgAction>, action: MgAction) {
@@ -52,13 +101,40 @@ pub struct MgApplication {
     window: gtk::ApplicationWindow,
     content_box: gtk::Box,
     erase_checkbtn: gtk::CheckButton,
+    verify_erase_checkbtn: gtk::CheckButton,
+    shutoff_checkbtn: gtk::CheckButton,
+    logger_expander: gtk::Expander,
+    time_interval_spin: gtk::SpinButton,
+    distance_interval_spin: gtk::SpinButton,
+    speed_threshold_spin: gtk::SpinButton,
+    when_full_combo: gtk::ComboBoxText,
+    /// The check buttons of the fields to log, by field id.
+    log_field_checkbtns: Vec<(&'static str, gtk::CheckButton)>,
     model_combo: gtk::ComboBox,
     model_store: gtk::ListStore,
     port_combo: gtk::ComboBox,
     port_store: gtk::ListStore,
+    progress_box: gtk::Box,
+    progress_bar: gtk::ProgressBar,
+    history_view: gtk::TreeView,
+    history_store: gtk::ListStore,
+    summary_label: gtk::Label,
+    /// The current phase and when it started, to estimate the time left.
+    progress_start: Option<(drivers::Phase, Instant)>,
+    /// An operation is using the device. Set with the state, by all
+    /// the operations.
+    busy: bool,
+    /// To cancel the operation in progress.
+    cancel_token: Option<drivers::CancelToken>,
+    /// The port of the detected device, to select once the ports are listed.
+    detected_port: Option<String>,
+    /// The output of the automatic download in progress.
+    auto_output: Option<path::PathBuf>,
 
     device_manager: devices::Manager,
     prefs_store: glib::KeyFile,
+    /// The download history. None if it failed to load.
+    library: Option<library::Library>,
 
     output_dest_dir: path::PathBuf,
     sender: glib::Sender<MgAction>,
@@ -70,10 +146,48 @@ impl MgApplication {
         let window: gtk::ApplicationWindow = builder.get_object("main_window").unwrap();
         let content_box = builder.get_object::<gtk::Box>("content_box").unwrap();
         let erase_checkbtn: gtk::CheckButton = builder.get_object("erase_checkbtn").unwrap();
+        let verify_erase_checkbtn: gtk::CheckButton =
+            builder.get_object("verify_erase_checkbtn").unwrap();
+        let shutoff_checkbtn: gtk::CheckButton = builder.get_object("shutoff_checkbtn").unwrap();
+        let auto_download_checkbtn: gtk::CheckButton =
+            builder.get_object("auto_download_checkbtn").unwrap();
         let model_combo: gtk::ComboBox = builder.get_object("model_combo").unwrap();
         let port_combo: gtk::ComboBox = builder.get_object("port_combo").unwrap();
+        let progress_box: gtk::Box = builder.get_object("progress_box").unwrap();
+        let progress_bar: gtk::ProgressBar = builder.get_object("progress_bar").unwrap();
         let output_dir_chooser: gtk::FileChooserButton =
             builder.get_object("output_dir_chooser").unwrap();
+        let split_combo: gtk::ComboBoxText = builder.get_object("split_combo").unwrap();
+        let template_entry: gtk::Entry = builder.get_object("template_entry").unwrap();
+        let formats_box: gtk::FlowBox = builder.get_object("formats_box").unwrap();
+        let format_checkbtns: Vec<(Format, gtk::CheckButton)> = Format::ALL
+            .iter()
+            .map(|format| {
+                let checkbtn = gtk::CheckButton::with_label(format.label());
+                formats_box.add(&checkbtn);
+                (*format, checkbtn)
+            })
+            .collect();
+        formats_box.show_all();
+        let logger_expander: gtk::Expander = builder.get_object("logger_expander").unwrap();
+        let time_interval_spin: gtk::SpinButton = builder.get_object("time_interval_spin").unwrap();
+        let distance_interval_spin: gtk::SpinButton =
+            builder.get_object("distance_interval_spin").unwrap();
+        let speed_threshold_spin: gtk::SpinButton =
+            builder.get_object("speed_threshold_spin").unwrap();
+        let when_full_combo: gtk::ComboBoxText = builder.get_object("when_full_combo").unwrap();
+        let log_fields_box: gtk::FlowBox = builder.get_object("log_fields_box").unwrap();
+        let log_field_checkbtns: Vec<(&'static str, gtk::CheckButton)> = logsettings::FIELDS
+            .iter()
+            .map(|(id, label)| {
+                let checkbtn = gtk::CheckButton::with_label(label);
+                log_fields_box.add(&checkbtn);
+                (*id, checkbtn)
+            })
+            .collect();
+        log_fields_box.show_all();
+        let history_view: gtk::TreeView = builder.get_object("history_view").unwrap();
+        let summary_label: gtk::Label = builder.get_object("summary_label").unwrap();
 
         gapp.add_window(&window);
 
@@ -107,6 +221,118 @@ impl MgApplication {
         erase_action.set_enabled(false);
         window.add_action(&erase_action);
 
+        // The state is changed once the device did.
+        let logging_action = gio::SimpleAction::new_stateful("logging", None, &false.to_variant());
+        let sender2 = sender.clone();
+        logging_action.connect_change_state(move |_, value| {
+            if let Some(enable) = value.and_then(|value| value.get::<bool>()) {
+                post_event(&sender2, MgAction::SetLogging(enable));
+            }
+        });
+        logging_action.set_enabled(false);
+        window.add_action(&logging_action);
+
+        let shutoff_action = gio::SimpleAction::new("shutoff", None);
+        let sender2 = sender.clone();
+        shutoff_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::StartShutoff);
+        });
+        shutoff_action.set_enabled(false);
+        window.add_action(&shutoff_action);
+
+        let read_log_settings_action = gio::SimpleAction::new("read-log-settings", None);
+        let sender2 = sender.clone();
+        read_log_settings_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::ReadLogSettings);
+        });
+        read_log_settings_action.set_enabled(false);
+        window.add_action(&read_log_settings_action);
+
+        let write_log_settings_action = gio::SimpleAction::new("write-log-settings", None);
+        let sender2 = sender.clone();
+        write_log_settings_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::WriteLogSettings);
+        });
+        write_log_settings_action.set_enabled(false);
+        window.add_action(&write_log_settings_action);
+
+        let cancel_action = gio::SimpleAction::new("cancel", None);
+        let sender2 = sender.clone();
+        cancel_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::CancelOperation);
+        });
+        window.add_action(&cancel_action);
+
+        let open_track_action = gio::SimpleAction::new("open-track", None);
+        let sender2 = sender.clone();
+        open_track_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::OpenTrack);
+        });
+        open_track_action.set_enabled(false);
+        window.add_action(&open_track_action);
+
+        let export_track_action = gio::SimpleAction::new("export-track", None);
+        let sender2 = sender.clone();
+        export_track_action.connect_activate(move |_, _| {
+            post_event(&sender2, MgAction::ExportTrack);
+        });
+        export_track_action.set_enabled(false);
+        window.add_action(&export_track_action);
+
+        let sender2 = sender.clone();
+        history_view
+            .get_selection()
+            .connect_changed(move |selection| {
+                let selected = selection.count_selected_rows() > 0;
+                open_track_action.set_enabled(selected);
+                export_track_action.set_enabled(selected);
+                post_event(&sender2, MgAction::HistorySelectionChanged);
+            });
+        let sender2 = sender.clone();
+        history_view.connect_row_activated(move |_, _, _| {
+            post_event(&sender2, MgAction::OpenTrack);
+        });
+
+        let sender2 = sender.clone();
+        verify_erase_checkbtn.connect_toggled(move |w| {
+            post_event(&sender2, MgAction::SetVerifyErase(w.get_active()));
+        });
+
+        let sender2 = sender.clone();
+        shutoff_checkbtn.connect_toggled(move |w| {
+            post_event(&sender2, MgAction::SetShutoff(w.get_active()));
+        });
+
+        let sender2 = sender.clone();
+        auto_download_checkbtn.connect_toggled(move |w| {
+            post_event(&sender2, MgAction::SetAutoDownload(w.get_active()));
+        });
+
+        let sender2 = sender.clone();
+        split_combo.connect_changed(move |w| {
+            if let Some(id) = w.get_active_id() {
+                post_event(&sender2, MgAction::SetSplitMode(id.to_string()));
+            }
+        });
+
+        for (_, checkbtn) in &format_checkbtns {
+            let sender2 = sender.clone();
+            let checkbtns = format_checkbtns.clone();
+            checkbtn.connect_toggled(move |_| {
+                let formats = checkbtns
+                    .iter()
+                    .filter(|(_, checkbtn)| checkbtn.get_active())
+                    .map(|(format, _)| *format)
+                    .collect();
+                post_event(&sender2, MgAction::SetFormats(formats));
+            });
+        }
+
+        let sender2 = sender.clone();
+        template_entry.connect_changed(move |w| {
+            post_event(&sender2, MgAction::SetTemplate(w.get_text().to_string()));
+        });
+
         let sender2 = sender.clone();
         output_dir_chooser.connect_file_set(move |w| {
             let file_name = w.get_filename();
@@ -124,18 +350,57 @@ impl MgApplication {
                     println!("received event {} {}", action, subsystem);
                 }
                 post_event(&sender2, MgAction::RescanDevices);
+                if action == "add" {
+                    if let Some(file) = device.get_device_file() {
+                        post_event(&sender2, MgAction::DeviceAdded(file.to_string()));
+                    }
+                }
             });
 
+        let library = match library::Library::load(&library::Library::default_path()) {
+            Ok(library) => Some(library),
+            Err(err) => {
+                println!("Error loading the library: {}", err);
+                None
+            }
+        };
+
         let app = MgApplication {
             window,
             content_box,
             erase_checkbtn,
+            verify_erase_checkbtn,
+            shutoff_checkbtn,
+            logger_expander,
+            time_interval_spin,
+            distance_interval_spin,
+            speed_threshold_spin,
+            when_full_combo,
+            log_field_checkbtns,
             model_combo,
             model_store: gtk::ListStore::new(&[glib::Type::String, glib::Type::String]),
             port_combo,
             port_store: gtk::ListStore::new(&[glib::Type::String, glib::Type::String]),
+            progress_box,
+            progress_bar,
+            history_view,
+            history_store: gtk::ListStore::new(&[
+                glib::Type::String,
+                glib::Type::String,
+                glib::Type::String,
+                glib::Type::String,
+                glib::Type::String,
+                glib::Type::U64,
+            ]),
+            summary_label,
+            progress_start: None,
+            busy: false,
+            cancel_token: None,
+            detected_port: None,
+            auto_output: None,
             device_manager,
             prefs_store: glib::KeyFile::new(),
+            library,
             output_dest_dir: path::PathBuf::new(),
             sender,
         };
@@ -152,8 +417,38 @@ impl MgApplication {
             println!("Error loading settings");
         }
 
-        if let Ok(output_dir) = me.borrow().prefs_store.get_string("output", "dir") {
+        if let Ok(gpsbabel_path) = me.borrow().prefs_store.get_string("gpsbabel", "path") {
+            gpsbabel::set_path(Some(path::Path::new(gpsbabel_path.as_str())));
+        }
+        gpsbabel::set_timeouts(me.borrow().timeouts());
+        let output_dir = me.borrow().prefs_store.get_string("output", "dir");
+        if let Ok(output_dir) = output_dir {
             output_dir_chooser.set_current_folder(output_dir.to_string());
+            me.borrow_mut()
+                .set_output_destination_dir(path::Path::new(output_dir.as_str()));
+        }
+        if let Ok(verify_erase) = me
+            .borrow()
+            .prefs_store
+            .get_boolean("device", "verify_erase")
+        {
+            me.borrow().verify_erase_checkbtn.set_active(verify_erase);
+        }
+        if let Ok(shutoff) = me.borrow().prefs_store.get_boolean("device", "shutoff") {
+            me.borrow().shutoff_checkbtn.set_active(shutoff);
+        }
+        if let Ok(split) = me.borrow().prefs_store.get_string("output", "split") {
+            split_combo.set_active_id(Some(split.as_str()));
+        }
+        let formats = me.borrow().output_formats();
+        for (format, checkbtn) in &format_checkbtns {
+            checkbtn.set_active(formats.contains(format));
+        }
+        if let Ok(template) = me.borrow().prefs_store.get_string("output", "template") {
+            template_entry.set_text(template.as_str());
+        }
+        if let Ok(auto_download) = me.borrow().prefs_store.get_boolean("auto", "download") {
+            auto_download_checkbtn.set_active(auto_download);
         }
         me
     }
@@ -164,7 +459,23 @@ impl MgApplication {
             println!("nodriver");
             post_event(
                 &self.sender,
-                MgAction::DoneDownload(drivers::Error::NoDriver),
+                MgAction::DoneDownload(Err(drivers::Error::NoDriver)),
+            );
+            return;
+        }
+        let erase = self.erase_checkbtn.get_active();
+        // Ask for a file only without an output directory.
+        if !self.output_dest_dir.as_os_str().is_empty() {
+            let destination = self.destination(self.output_dest_dir.clone(), false);
+            self.download_to(device.unwrap(), destination, erase);
+            return;
+        }
+        if self.splitter().is_some() {
+            post_event(
+                &self.sender,
+                MgAction::DoneDownload(Err(drivers::Error::Failed(
+                    "No output directory is set.".to_string(),
+                ))),
             );
             return;
         }
@@ -189,7 +500,7 @@ impl MgApplication {
             } else {
                 post_event(
                     &self.sender,
-                    MgAction::DoneDownload(drivers::Error::Cancelled),
+                    MgAction::DoneDownload(Err(drivers::Error::Cancelled)),
                 );
                 return;
             }
@@ -197,32 +508,530 @@ impl MgApplication {
             chooser.close();
             post_event(
                 &self.sender,
-                MgAction::DoneDownload(drivers::Error::Cancelled),
+                MgAction::DoneDownload(Err(drivers::Error::Cancelled)),
             );
             return;
         }
-        let mut d = device.unwrap();
+        self.download_to(device.unwrap(), Destination::File(output_file), erase);
+    }
+
+    /// The output formats set in the settings, GPX by default.
+    fn output_formats(&self) -> Vec<Format> {
+        // `format` is the single format of older settings.
+        let formats: Vec<Format> = self
+            .prefs_store
+            .get_string_list("output", "formats")
+            .or_else(|_| self.prefs_store.get_string_list("output", "format"))
+            .map(|ids| ids.iter().filter_map(|id| Format::from_id(id)).collect())
+            .unwrap_or_default();
+        if formats.is_empty() {
+            vec![Format::Gpx]
+        } else {
+            formats
+        }
+    }
+
+    /// The gpsbabel timeouts set in the settings, in seconds in the
+    /// `timeouts` group. 0 is no timeout.
+    fn timeouts(&self) -> gpsbabel::Timeouts {
+        let timeout = |key: &str, default: Option<Duration>| match self
+            .prefs_store
+            .get_uint64("timeouts", key)
+        {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => default,
+        };
+        let default = gpsbabel::Timeouts::DEFAULT;
+        gpsbabel::Timeouts {
+            download: timeout("download", default.download),
+            erase: timeout("erase", default.erase),
+            convert: timeout("convert", default.convert),
+            command: timeout("command", default.command),
+            stall: timeout("stall", default.stall),
+        }
+    }
+
+    /// The gpsbabel filters set in the settings for the device `model`,
+    /// in the `filters` group.
+    fn filters(&self, model: &str) -> Result<Vec<gpsbabel::Filter>, String> {
+        match self.prefs_store.get_string("filters", model) {
+            Ok(filters) => gpsbabel::Filter::parse_list(&filters, ';'),
+            Err(_) => Ok(vec![]),
+        }
+    }
+
+    /// The offset of the timezone set in the settings, local by default.
+    fn timezone_offset(&self) -> template::Offset {
+        let timezone = self.prefs_store.get_string("output", "timezone").ok();
+        template::timezone(timezone.as_deref())
+    }
+
+    /// The destination in `dir` from the settings. For the automatic
+    /// download, `auto`, the template can be overridden.
+    fn destination(&self, dir: path::PathBuf, auto: bool) -> Destination {
+        if let Some(splitter) = self.splitter() {
+            return Destination::Split(dir, splitter);
+        }
+        let auto_template = if auto {
+            self.prefs_store.get_string("auto", "template").ok()
+        } else {
+            None
+        };
+        let pattern = auto_template
+            .or_else(|| self.prefs_store.get_string("output", "template").ok())
+            .map(|template| template.to_string())
+            .filter(|template| !template.is_empty())
+            .unwrap_or_else(|| template::DEFAULT_TEMPLATE.to_string());
+        Destination::Named(
+            dir,
+            template::Template::new(&pattern, self.timezone_offset()),
+        )
+    }
+
+    /// The splitter of the downloads set in the settings, None if
+    /// they aren't split.
+    fn splitter(&self) -> Option<split::Splitter> {
+        let mode = self
+            .prefs_store
+            .get_string("output", "split")
+            .ok()
+            .and_then(|id| split::Mode::from_id(&id))
+            .unwrap_or(split::Mode::None);
+        if mode == split::Mode::None {
+            return None;
+        }
+        let trip_gap = self
+            .prefs_store
+            .get_integer("output", "trip_gap")
+            .map(|minutes| i64::from(minutes) * 60)
+            .unwrap_or(split::DEFAULT_TRIP_GAP);
+        let pattern = self
+            .prefs_store
+            .get_string("output", "split_template")
+            .map(|template| template.to_string())
+            .unwrap_or_else(|_| mode.default_template().to_string());
+        Some(split::Splitter {
+            mode,
+            trip_gap,
+            template: template::Template::new(&pattern, self.timezone_offset()),
+        })
+    }
+
+    /// Download from the device `d` to `destination`, in a thread.
+    /// If `erase`, the device is erased once the data is saved and,
+    /// unless disabled in the settings, checked. A device that can only
+    /// be erased while downloading is erased unchecked. It is then turned
+    /// off if set in the settings.
+    fn download_to(
+        &mut self,
+        mut d: Arc<dyn drivers::Driver + Send + Sync>,
+        destination: Destination,
+        erase: bool,
+    ) {
+        let formats = self.output_formats();
+        if let Destination::Split(..) = destination {
+            if formats != [Format::Gpx] {
+                post_event(
+                    &self.sender,
+                    MgAction::DoneDownload(Err(drivers::Error::Failed(
+                        "Only GPX downloads can be split.".to_string(),
+                    ))),
+                );
+                return;
+            }
+        }
+        let model = self.device_manager.model().unwrap_or_default().to_string();
+        let filters = match self.filters(&model) {
+            Ok(filters) => filters,
+            Err(message) => {
+                post_event(
+                    &self.sender,
+                    MgAction::DoneDownload(Err(drivers::Error::Failed(message))),
+                );
+                return;
+            }
+        };
         let sender = self.sender.clone();
+        let cancel = drivers::CancelToken::new();
+        self.cancel_token = Some(cancel.clone());
+        let port = self.device_manager.port().unwrap_or_default().to_string();
+        let verify = self
+            .prefs_store
+            .get_boolean("device", "verify_erase")
+            .unwrap_or(true);
+        let converted = !filters.is_empty() || formats != [Format::Gpx];
+        let erase = match self.device_manager.device_capability(&model) {
+            Some(capability) => match verify::erase_mode(&capability, erase, verify, converted) {
+                Ok(erase) => erase,
+                Err(e) => {
+                    post_event(&self.sender, MgAction::DoneDownload(Err(e)));
+                    return;
+                }
+            },
+            None => verify::Erase::No,
+        };
+        let can_shutoff = self
+            .device_manager
+            .device_capability(&model)
+            .map(|cap| cap.can_shutoff)
+            .unwrap_or(false);
+        let shutoff = can_shutoff
+            && self
+                .prefs_store
+                .get_boolean("device", "shutoff")
+                .unwrap_or(false);
         thread::spawn(move || {
-            post_event(
-                &sender,
-                if Arc::get_mut(&mut d).unwrap().open() {
-                    match d.download(Format::Gpx, false) {
-                        Ok(temp_output_filename) => {
-                            println!("success {}", temp_output_filename.to_str().unwrap());
-                            if let Err(e) = std::fs::copy(temp_output_filename, &output_file) {
-                                MgAction::DoneDownload(drivers::Error::IOError(e))
-                            } else {
-                                MgAction::DoneDownload(drivers::Error::Success)
-                            }
-                        }
-                        Err(e) => MgAction::DoneDownload(e),
-                    }
+            let sender2 = sender.clone();
+            let progress = move |p| post_event(&sender2, MgAction::Progress(p));
+            if let Err(e) = Arc::get_mut(&mut d).unwrap().open() {
+                post_event(&sender, MgAction::DoneDownload(Err(e)));
+                return;
+            }
+            let result = drivers::download_formats(
+                &*d,
+                &formats,
+                &filters,
+                erase == verify::Erase::WhileDownloading,
+                &progress,
+                &cancel,
+            )
+            .and_then(|temp_outputs| Self::save_download(temp_outputs, &destination, &model));
+            for (format, output_file) in result.iter().flatten() {
+                match library::Entry::from_file(&model, &port, *format, output_file) {
+                    Ok(entry) => post_event(&sender, MgAction::AddToLibrary(entry)),
+                    Err(e) => println!("Can't add {:?} to the library: {}", output_file, e),
+                }
+            }
+            let result = result.and_then(|outputs| match erase {
+                verify::Erase::AfterSaving { check } => {
+                    verify::erase_after_saving(&*d, &outputs, check, &progress, &cancel)
+                }
+                _ => Ok(()),
+            });
+            let result = result.and_then(|_| {
+                if shutoff {
+                    drivers::shutoff_after_download(&*d, &progress, &cancel)
                 } else {
-                    MgAction::DoneErase(drivers::Error::Failed("open failed".to_string()))
+                    Ok(())
+                }
+            });
+            post_event(&sender, MgAction::DoneDownload(result));
+        });
+    }
+
+    /// Save the downloaded files of each format to `destination`.
+    /// Return the files written.
+    fn save_download(
+        temp_outputs: Vec<(Format, TempFile)>,
+        destination: &Destination,
+        model: &str,
+    ) -> Result<Vec<(Format, path::PathBuf)>, drivers::Error> {
+        let mut outputs = vec![];
+        for (index, (format, temp_output)) in temp_outputs.into_iter().enumerate() {
+            println!("success {:?}", temp_output.path());
+            match *destination {
+                // The other formats are next to the first one.
+                Destination::File(ref output) => {
+                    let output = if index == 0 {
+                        output.clone()
+                    } else {
+                        output.with_extension(template::extension(format))
+                    };
+                    temp_output
+                        .persist(&output)
+                        .map_err(drivers::Error::IOError)?;
+                    outputs.push((format, output));
+                }
+                Destination::Named(ref dir, ref template) => {
+                    let output = template::save_in(temp_output, format, dir, template, model)?;
+                    outputs.push((format, output));
+                }
+                Destination::Split(ref dir, ref splitter) => {
+                    for output in splitter.save(temp_output.path(), dir, model)? {
+                        outputs.push((format, output));
+                    }
+                }
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Fill the history view from the library, most recent first.
+    fn populate_history(&mut self) {
+        self.history_store.clear();
+        let entries = match self.library {
+            Some(ref library) => library.entries(),
+            None => return,
+        };
+        for (index, entry) in entries.iter().enumerate().rev() {
+            let date = glib::DateTime::from_unix_local(entry.timestamp as i64)
+                .format("%x %X")
+                .map(|date| date.to_string())
+                .unwrap_or_default();
+            let file = entry
+                .output
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            self.history_store.insert_with_values(
+                None,
+                &[
+                    HISTORY_COL_DATE,
+                    HISTORY_COL_MODEL,
+                    HISTORY_COL_POINTS,
+                    HISTORY_COL_FILE,
+                    HISTORY_COL_PATH,
+                    HISTORY_COL_INDEX,
+                ],
+                &[
+                    &date,
+                    &entry.model,
+                    &entry.points.to_string(),
+                    &file,
+                    &entry.output.to_string_lossy().into_owned(),
+                    &(index as u64),
+                ],
+            );
+        }
+    }
+
+    /// Record the download in the library.
+    fn add_to_library(&mut self, entry: library::Entry) {
+        if let Some(ref mut library) = self.library {
+            if let Err(err) = library.add(entry) {
+                println!("Error saving the library: {}", err);
+            }
+        }
+        self.populate_history();
+        // Select the new entry to show its summary.
+        if let Some(iter) = self.history_store.get_iter_first() {
+            self.history_view.get_selection().select_iter(&iter);
+        }
+    }
+
+    /// Format the statistics for display.
+    fn format_summary(stats: &stats::Stats) -> String {
+        if stats.is_empty() {
+            return "No points.".to_string();
+        }
+        let format_time = |time: track::Time| {
+            glib::DateTime::from_unix_local(time.unix())
+                .format("%x %X")
+                .map(|time| time.to_string())
+                .unwrap_or_default()
+        };
+        let mut lines = vec![format!(
+            "{} tracks, {} points, {} waypoints",
+            stats.tracks, stats.points, stats.waypoints
+        )];
+        if let (Some(start), Some(end)) = (stats.start_time, stats.end_time) {
+            lines.push(format!(
+                "From {} to {}",
+                format_time(start),
+                format_time(end)
+            ));
+        }
+        lines.push(format!(
+            "Distance: {:.2} km, moving time: {}",
+            stats.distance / 1000.0,
+            Self::format_duration(stats.moving_time as u64)
+        ));
+        lines.push(format!(
+            "Speed: max {:.1} km/h, average {:.1} km/h",
+            stats.max_speed * 3.6,
+            stats.avg_speed * 3.6
+        ));
+        lines.push(format!("Elevation gain: {:.0} m", stats.elevation_gain));
+        if let Some(ref bounds) = stats.bounds {
+            lines.push(format!(
+                "Area: {:.5}, {:.5} to {:.5}, {:.5}",
+                bounds.min_lat, bounds.min_lon, bounds.max_lat, bounds.max_lon
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Show the summary of the track selected in the history.
+    fn update_summary(&self) {
+        let text = match self.selected_entry() {
+            Some(ref entry) if entry.format.as_deref().unwrap_or("gpx") != "gpx" => {
+                "No summary for this format.".to_string()
+            }
+            Some(entry) => match std::fs::read_to_string(&entry.output) {
+                Ok(content) => match gpx::read(&content) {
+                    Ok(document) => Self::format_summary(&stats::Stats::compute(&document)),
+                    Err(err) => format!("Invalid GPX: {}", err),
                 },
+                Err(err) => format!("Can't read {}: {}", entry.output.display(), err),
+            },
+            None => String::new(),
+        };
+        self.summary_label.set_text(&text);
+    }
+
+    /// The library entry selected in the history.
+    fn selected_entry(&self) -> Option<library::Entry> {
+        let (model, iter) = self.history_view.get_selection().get_selected()?;
+        let index = model
+            .get_value(&iter, HISTORY_COL_INDEX as i32)
+            .get_some::<u64>()
+            .ok()?;
+        self.library
+            .as_ref()?
+            .entries()
+            .get(index as usize)
+            .cloned()
+    }
+
+    /// Open the selected track with the default application.
+    fn open_track(&self) {
+        let entry = match self.selected_entry() {
+            Some(entry) => entry,
+            None => return,
+        };
+        if !entry.output.exists() {
+            self.report_error(
+                "Error opening the track.",
+                &format!("{} doesn't exist anymore.", entry.output.display()),
             );
-        });
+            return;
+        }
+        let uri = gio::File::new_for_path(&entry.output).get_uri();
+        if let Err(err) = gio::AppInfo::launch_default_for_uri(&uri, None::<&gio::AppLaunchContext>)
+        {
+            self.report_error("Error opening the track.", &err.to_string());
+        }
+    }
+
+    /// Save a copy of the selected track.
+    fn export_track(&self) {
+        let entry = match self.selected_entry() {
+            Some(entry) => entry,
+            None => return,
+        };
+        let chooser = gtk::FileChooserDialog::new(
+            Some("Export Track"),
+            Some(&self.window),
+            gtk::FileChooserAction::Save,
+        );
+        chooser.add_buttons(&[
+            ("Save", gtk::ResponseType::Ok),
+            ("Cancel", gtk::ResponseType::Cancel),
+        ]);
+        chooser.set_do_overwrite_confirmation(true);
+        if let Some(name) = entry.output.file_name() {
+            chooser.set_current_name(name);
+        }
+        let response = chooser.run();
+        let dest = chooser.get_filename();
+        chooser.close();
+        if response != gtk::ResponseType::Ok {
+            return;
+        }
+        if let Some(dest) = dest {
+            if let Err(err) = std::fs::copy(&entry.output, &dest) {
+                self.report_error("Error exporting the track.", &err.to_string());
+            }
+        }
+    }
+
+    /// A device was plugged on `port`. Download from it if automatic
+    /// download is enabled and it is the remembered device.
+    fn device_added(&mut self, port: &str) {
+        if !self
+            .prefs_store
+            .get_boolean("auto", "download")
+            .unwrap_or(false)
+        {
+            return;
+        }
+        if self.busy {
+            println!("Busy, not downloading from {}", port);
+            return;
+        }
+        let model = match self.prefs_store.get_string("device", "model") {
+            Ok(model) => model.to_string(),
+            Err(_) => return,
+        };
+        if self
+            .prefs_store
+            .get_string("device", "port")
+            .ok()
+            .as_deref()
+            != Some(port)
+        {
+            return;
+        }
+        // Another known device might have been plugged on the same port.
+        let detections: Vec<devices::Detection> = self
+            .device_manager
+            .detect()
+            .into_iter()
+            .filter(|detection| detection.port.path == path::Path::new(port))
+            .collect();
+        if !detections.is_empty() && !detections.iter().any(|d| d.model == model) {
+            println!("{} is not a {}, not downloading", port, model);
+            return;
+        }
+        let output_dir = match self.prefs_store.get_string("output", "dir") {
+            Ok(dir) => path::PathBuf::from(dir.as_str()),
+            Err(_) => {
+                self.notify("Error downloading GPS data.", "No output directory is set.");
+                return;
+            }
+        };
+        let can_erase = self
+            .device_manager
+            .device_capability(&model)
+            .map(|cap| cap.can_erase)
+            .unwrap_or(false);
+        let erase = can_erase
+            && self
+                .prefs_store
+                .get_boolean("auto", "erase")
+                .unwrap_or(false);
+
+        self.device_manager.set_model(&model);
+        self.device_manager.set_port(port);
+        let device = match self.device_manager.get_device() {
+            Some(device) => device,
+            None => {
+                self.notify(
+                    "Error downloading GPS data.",
+                    &drivers::Error::NoDriver.to_string(),
+                );
+                return;
+            }
+        };
+        println!("Automatic download from {} to {:?}", port, output_dir);
+        self.set_state(UIState::InProgress);
+        self.auto_output = Some(output_dir.clone());
+        let destination = self.destination(output_dir, true);
+        self.download_to(device, destination, erase);
+    }
+
+    /// Send a desktop notification.
+    fn notify(&self, title: &str, body: &str) {
+        let notification = gio::Notification::new(title);
+        notification.set_body(Some(body));
+        if let Some(gapp) = self.window.get_application() {
+            gapp.send_notification(Some("download"), &notification);
+        }
+    }
+
+    /// The title of the error message for `err`, `default` unless the
+    /// problem is with the device or its port.
+    fn error_title<'a>(err: &drivers::Error, default: &'a str) -> &'a str {
+        match *err {
+            drivers::Error::Timeout(_) => "The GPS device timed out.",
+            drivers::Error::NotResponding => "The GPS device isn't responding.",
+            drivers::Error::PortBusy(_) | drivers::Error::PermissionDenied(_) => {
+                "Can't open the port of the GPS device."
+            }
+            drivers::Error::MissingTool(_) => "gpsbabel is needed.",
+            _ => default,
+        }
     }
 
     fn report_error(&self, message: &str, reason: &str) {
@@ -238,33 +1047,147 @@ impl MgApplication {
         dialog.close();
     }
 
-    fn do_erase(&self) {
+    fn do_erase(&mut self) {
+        let device = self.device_manager.get_device();
+        if device.is_none() {
+            println!("nodriver");
+            post_event(
+                &self.sender,
+                MgAction::DoneErase(Err(drivers::Error::NoDriver)),
+            );
+            return;
+        }
+        let mut d = device.unwrap();
+        let sender = self.sender.clone();
+        let cancel = drivers::CancelToken::new();
+        self.cancel_token = Some(cancel.clone());
+        thread::spawn(move || {
+            let sender2 = sender.clone();
+            let progress = move |p| post_event(&sender2, MgAction::Progress(p));
+            let result = Arc::get_mut(&mut d)
+                .unwrap()
+                .open()
+                .and_then(|_| d.erase(&progress, &cancel));
+            if result.is_ok() {
+                println!("success erasing");
+            }
+            post_event(&sender, MgAction::DoneErase(result));
+        });
+    }
+
+    fn do_shutoff(&mut self) {
         let device = self.device_manager.get_device();
         if device.is_none() {
             println!("nodriver");
-            post_event(&self.sender, MgAction::DoneErase(drivers::Error::NoDriver));
+            post_event(
+                &self.sender,
+                MgAction::DoneShutoff(Err(drivers::Error::NoDriver)),
+            );
             return;
         }
         let mut d = device.unwrap();
         let sender = self.sender.clone();
+        let cancel = drivers::CancelToken::new();
+        self.cancel_token = Some(cancel.clone());
         thread::spawn(move || {
+            let sender2 = sender.clone();
+            let progress = move |p| post_event(&sender2, MgAction::Progress(p));
+            let result = Arc::get_mut(&mut d)
+                .unwrap()
+                .open()
+                .and_then(|_| d.shutoff(&progress, &cancel));
+            post_event(&sender, MgAction::DoneShutoff(result));
+        });
+    }
+
+    fn do_set_logging(&mut self, enable: bool) {
+        let device = self.device_manager.get_device();
+        if device.is_none() {
+            println!("nodriver");
             post_event(
-                &sender,
-                if Arc::get_mut(&mut d).unwrap().open() {
-                    match d.erase() {
-                        Ok(_) => {
-                            println!("success erasing");
-                            MgAction::DoneErase(drivers::Error::Success)
-                        }
-                        Err(e) => MgAction::DoneErase(e),
-                    }
-                } else {
-                    MgAction::DoneErase(drivers::Error::Failed("open failed".to_string()))
-                },
+                &self.sender,
+                MgAction::DoneSetLogging(Err(drivers::Error::NoDriver)),
+            );
+            return;
+        }
+        let mut d = device.unwrap();
+        let sender = self.sender.clone();
+        let cancel = drivers::CancelToken::new();
+        self.cancel_token = Some(cancel.clone());
+        thread::spawn(move || {
+            let sender2 = sender.clone();
+            let progress = move |p| post_event(&sender2, MgAction::Progress(p));
+            let result = Arc::get_mut(&mut d)
+                .unwrap()
+                .open()
+                .and_then(|_| d.set_logging(enable, &progress, &cancel))
+                // Trust the device if it can tell.
+                .map(|_| d.logging().unwrap_or(enable));
+            post_event(&sender, MgAction::DoneSetLogging(result));
+        });
+    }
+
+    /// Read the logging settings of the device, after writing
+    /// `settings` if any, in a thread.
+    fn do_log_settings(&mut self, settings: Option<LogSettings>) {
+        let device = self.device_manager.get_device();
+        if device.is_none() {
+            println!("nodriver");
+            post_event(
+                &self.sender,
+                MgAction::DoneLogSettings(Err(drivers::Error::NoDriver)),
             );
+            return;
+        }
+        let mut d = device.unwrap();
+        let sender = self.sender.clone();
+        let cancel = drivers::CancelToken::new();
+        self.cancel_token = Some(cancel.clone());
+        thread::spawn(move || {
+            let result = Arc::get_mut(&mut d).unwrap().open().and_then(|_| {
+                if let Some(ref settings) = settings {
+                    d.set_log_settings(settings, &cancel)?;
+                }
+                d.log_settings(&cancel)
+            });
+            post_event(&sender, MgAction::DoneLogSettings(result));
         });
     }
 
+    /// The logging settings in the UI.
+    fn ui_log_settings(&self) -> LogSettings {
+        LogSettings {
+            time_interval: self.time_interval_spin.get_value(),
+            distance_interval: self.distance_interval_spin.get_value(),
+            speed_threshold: self.speed_threshold_spin.get_value(),
+            fields: self
+                .log_field_checkbtns
+                .iter()
+                .filter(|(_, checkbtn)| checkbtn.get_active())
+                .map(|(id, _)| id.to_string())
+                .collect(),
+            when_full: self
+                .when_full_combo
+                .get_active_id()
+                .and_then(|id| WhenFull::from_id(&id))
+                .unwrap_or(WhenFull::Overwrite),
+        }
+    }
+
+    /// Show the logging `settings` in the UI.
+    fn show_log_settings(&self, settings: &LogSettings) {
+        self.time_interval_spin.set_value(settings.time_interval);
+        self.distance_interval_spin
+            .set_value(settings.distance_interval);
+        self.speed_threshold_spin
+            .set_value(settings.speed_threshold);
+        for (id, checkbtn) in &self.log_field_checkbtns {
+            checkbtn.set_active(settings.fields.iter().any(|field| field == id));
+        }
+        self.when_full_combo
+            .set_active_id(Some(settings.when_full.id()));
+    }
+
     fn settings_dir() -> path::PathBuf {
         // XXX replace this by glib stuff when we can.
         // Also we treat a failure of this as fatal.
@@ -308,8 +1231,22 @@ impl MgApplication {
     pub fn start(&mut self) {
         utils::setup_text_combo(&self.model_combo, &self.model_store);
         utils::setup_text_combo(&self.port_combo, &self.port_store);
+        self.history_view.set_model(Some(&self.history_store));
+        self.history_view
+            .set_tooltip_column(HISTORY_COL_PATH as i32);
+        utils::add_text_column(&self.history_view, "Date", HISTORY_COL_DATE as i32);
+        utils::add_text_column(&self.history_view, "Device", HISTORY_COL_MODEL as i32);
+        utils::add_text_column(&self.history_view, "Points", HISTORY_COL_POINTS as i32);
+        utils::add_text_column(&self.history_view, "File", HISTORY_COL_FILE as i32);
+        self.populate_history();
         self.populate_model_combo();
         self.window.show_all();
+
+        let db_errors = self.device_manager.db_errors();
+        if !db_errors.is_empty() {
+            let reason: Vec<String> = db_errors.iter().map(|e| e.to_string()).collect();
+            self.report_error("Error loading device definitions.", &reason.join("\n"));
+        }
     }
 
     /// Rescan devices. On start and when new device is connected.
@@ -321,7 +1258,12 @@ impl MgApplication {
         self.port_store.clear();
         for port in ports {
             println!("adding port {:?}", port);
-            utils::add_text_row(&self.port_store, &port.path.to_str().unwrap(), &port.id);
+            let problem = port.access.as_ref().and_then(|access| access.problem());
+            let label = match problem {
+                Some(problem) => format!("{} ({})", port.id, problem),
+                None => port.id.clone(),
+            };
+            utils::add_text_row(&self.port_store, &port.path.to_str().unwrap(), &label);
         }
     }
 
@@ -334,7 +1276,26 @@ impl MgApplication {
             }
         }
 
-        if let Ok(model) = self.prefs_store.get_string("device", "model") {
+        let saved_model = self
+            .prefs_store
+            .get_string("device", "model")
+            .ok()
+            .map(|model| model.to_string());
+        let detections = self.device_manager.detect();
+        // Prefer the saved model if it is connected.
+        let detection = detections
+            .iter()
+            .find(|detection| Some(&detection.model) == saved_model.as_ref())
+            .or_else(|| detections.first());
+        if let Some(detection) = detection {
+            println!("detected {} on {:?}", detection.model, detection.port.path);
+            self.detected_port = detection.port.path.to_str().map(str::to_string);
+            self.model_combo
+                .set_active_id(Some(detection.model.as_ref()));
+            return;
+        }
+
+        if let Some(model) = saved_model {
             self.model_combo.set_active_id(Some(model.as_ref()));
         }
 
@@ -356,6 +1317,9 @@ impl MgApplication {
             self.device_manager.set_model(id);
             let ports = self.device_manager.get_ports_for_model(id);
             self.populate_port_combo(&ports.unwrap_or_default());
+            if let Some(port) = self.detected_port.take() {
+                self.port_combo.set_active_id(Some(port.as_ref()));
+            }
         } else {
             // XXX clear device.
         }
@@ -363,11 +1327,33 @@ impl MgApplication {
 
     fn update_device_capability(&self, capability: &devices::Capability) {
         self.erase_checkbtn.set_sensitive(capability.can_erase);
+        self.verify_erase_checkbtn
+            .set_sensitive(capability.can_erase);
         if let Some(a) = self.window.lookup_action("erase") {
             if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
                 sa.set_enabled(capability.can_erase_only);
             }
         }
+        if let Some(a) = self.window.lookup_action("logging") {
+            if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
+                sa.set_enabled(capability.can_log_enable);
+            }
+        }
+        self.shutoff_checkbtn.set_sensitive(capability.can_shutoff);
+        if let Some(a) = self.window.lookup_action("shutoff") {
+            if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
+                sa.set_enabled(capability.can_shutoff);
+            }
+        }
+        self.logger_expander
+            .set_sensitive(capability.can_configure());
+        for name in &["read-log-settings", "write-log-settings"] {
+            if let Some(a) = self.window.lookup_action(name) {
+                if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
+                    sa.set_enabled(capability.can_configure());
+                }
+            }
+        }
     }
 
     fn port_changed(&mut self, id: &str) {
@@ -387,10 +1373,68 @@ impl MgApplication {
     fn set_state(&mut self, state: UIState) {
         match state {
             UIState::Idle => {
+                self.busy = false;
                 self.content_box.set_sensitive(true);
+                self.progress_box.hide();
+                self.cancel_token = None;
             }
             UIState::InProgress => {
+                self.busy = true;
                 self.content_box.set_sensitive(false);
+                self.progress_start = None;
+                self.progress_bar.set_fraction(0.0);
+                self.progress_bar.set_text(None);
+                self.progress_box.set_sensitive(true);
+                self.progress_box.show();
+            }
+        }
+    }
+
+    /// Cancel the operation in progress, if any.
+    fn cancel_operation(&mut self) {
+        if let Some(ref cancel) = self.cancel_token {
+            cancel.cancel();
+            self.progress_box.set_sensitive(false);
+            self.progress_bar.set_text(Some("Cancelling"));
+        }
+    }
+
+    /// Format a duration in seconds as m:ss, or h:mm:ss
+    fn format_duration(secs: u64) -> String {
+        if secs >= 3600 {
+            format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+        } else {
+            format!("{}:{:02}", secs / 60, secs % 60)
+        }
+    }
+
+    /// Update the progress bar, and estimate the time left.
+    fn update_progress(&mut self, progress: &drivers::Progress) {
+        let start = match self.progress_start {
+            Some((phase, start)) if phase == progress.phase => start,
+            _ => {
+                let start = Instant::now();
+                self.progress_start = Some((progress.phase, start));
+                start
+            }
+        };
+        match progress.fraction() {
+            Some(fraction) => {
+                self.progress_bar.set_fraction(fraction);
+                let mut text = format!("{} {:.0}%", progress.phase.label(), fraction * 100.0);
+                if fraction > 0.0 {
+                    let elapsed = start.elapsed().as_secs_f64();
+                    let remaining = elapsed * (1.0 - fraction) / fraction;
+                    text.push_str(&format!(
+                        " - {} remaining",
+                        Self::format_duration(remaining as u64)
+                    ));
+                }
+                self.progress_bar.set_text(Some(&text));
+            }
+            None => {
+                self.progress_bar.pulse();
+                self.progress_bar.set_text(Some(progress.phase.label()));
             }
         }
     }
@@ -400,6 +1444,7 @@ impl MgApplication {
             MgAction::RescanDevices => {
                 self.rescan_devices();
             }
+            MgAction::DeviceAdded(ref port) => self.device_added(port),
             MgAction::ModelChanged(ref id) => {
                 self.model_changed(id);
             }
@@ -408,10 +1453,75 @@ impl MgApplication {
                 self.set_state(UIState::InProgress);
                 self.do_erase();
             }
-            MgAction::DoneErase(e) => {
-                match e {
-                    drivers::Error::Success | drivers::Error::Cancelled => {}
-                    _ => self.report_error("Error erasing GPS data.", &e.to_string()),
+            MgAction::DoneErase(result) => {
+                match result {
+                    Ok(()) | Err(drivers::Error::Cancelled) => {}
+                    Err(e) => self.report_error(
+                        Self::error_title(&e, "Error erasing GPS data."),
+                        &e.to_string(),
+                    ),
+                }
+                self.set_state(UIState::Idle);
+            }
+            MgAction::StartShutoff => {
+                self.set_state(UIState::InProgress);
+                self.do_shutoff();
+            }
+            MgAction::DoneShutoff(result) => {
+                match result {
+                    Ok(()) | Err(drivers::Error::Cancelled) => {}
+                    Err(e) => self.report_error(
+                        Self::error_title(&e, "Error turning off the GPS device."),
+                        &e.to_string(),
+                    ),
+                }
+                self.set_state(UIState::Idle);
+            }
+            MgAction::ReadLogSettings => {
+                self.set_state(UIState::InProgress);
+                self.do_log_settings(None);
+            }
+            MgAction::WriteLogSettings => {
+                let settings = self.ui_log_settings();
+                if settings.fields.is_empty() {
+                    self.report_error(
+                        "Error changing the logger settings.",
+                        "No field to record is checked.",
+                    );
+                } else {
+                    self.set_state(UIState::InProgress);
+                    self.do_log_settings(Some(settings));
+                }
+            }
+            MgAction::DoneLogSettings(result) => {
+                match result {
+                    Ok(settings) => self.show_log_settings(&settings),
+                    Err(drivers::Error::Cancelled) => {}
+                    Err(e) => self.report_error(
+                        Self::error_title(&e, "Error with the logger settings."),
+                        &e.to_string(),
+                    ),
+                }
+                self.set_state(UIState::Idle);
+            }
+            MgAction::SetLogging(enable) => {
+                self.set_state(UIState::InProgress);
+                self.do_set_logging(enable);
+            }
+            MgAction::DoneSetLogging(result) => {
+                match result {
+                    Ok(logging) => {
+                        if let Some(a) = self.window.lookup_action("logging") {
+                            if let Ok(sa) = a.downcast::<gio::SimpleAction>() {
+                                sa.set_state(&logging.to_variant());
+                            }
+                        }
+                    }
+                    Err(drivers::Error::Cancelled) => {}
+                    Err(e) => self.report_error(
+                        Self::error_title(&e, "Error changing the logging."),
+                        &e.to_string(),
+                    ),
                 }
                 self.set_state(UIState::Idle);
             }
@@ -419,13 +1529,75 @@ impl MgApplication {
                 self.set_state(UIState::InProgress);
                 self.do_download();
             }
-            MgAction::DoneDownload(e) => {
-                match e {
-                    drivers::Error::Success | drivers::Error::Cancelled => {}
-                    _ => self.report_error("Error downloading GPS data.", &e.to_string()),
+            MgAction::DoneDownload(result) => {
+                if let Some(output) = self.auto_output.take() {
+                    match result {
+                        Ok(()) => self.notify(
+                            "GPS data downloaded.",
+                            &format!("Saved to {}", output.display()),
+                        ),
+                        Err(e) => self.notify(
+                            Self::error_title(&e, "Error downloading GPS data."),
+                            &e.to_string(),
+                        ),
+                    }
+                } else {
+                    match result {
+                        Ok(()) | Err(drivers::Error::Cancelled) => {}
+                        Err(e) => self.report_error(
+                            Self::error_title(&e, "Error downloading GPS data."),
+                            &e.to_string(),
+                        ),
+                    }
                 }
                 self.set_state(UIState::Idle);
             }
+            MgAction::Progress(ref progress) => self.update_progress(progress),
+            MgAction::CancelOperation => self.cancel_operation(),
+            MgAction::AddToLibrary(entry) => self.add_to_library(entry),
+            MgAction::OpenTrack => self.open_track(),
+            MgAction::ExportTrack => self.export_track(),
+            MgAction::HistorySelectionChanged => self.update_summary(),
+            MgAction::SetAutoDownload(enabled) => {
+                self.prefs_store.set_boolean("auto", "download", enabled);
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
+            MgAction::SetVerifyErase(enabled) => {
+                self.prefs_store
+                    .set_boolean("device", "verify_erase", enabled);
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
+            MgAction::SetShutoff(enabled) => {
+                self.prefs_store.set_boolean("device", "shutoff", enabled);
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
+            MgAction::SetSplitMode(id) => {
+                self.prefs_store.set_string("output", "split", &id);
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
+            MgAction::SetFormats(formats) => {
+                // A list in the key file syntax.
+                let ids: Vec<&str> = formats.iter().map(|format| format.id()).collect();
+                self.prefs_store
+                    .set_string("output", "formats", &ids.join(";"));
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
+            MgAction::SetTemplate(template) => {
+                self.prefs_store.set_string("output", "template", &template);
+                if self.save_settings().is_err() {
+                    println!("Error loading settings");
+                }
+            }
             MgAction::SetOutputDir(f) => {
                 self.set_output_destination_dir(f.as_ref());
                 self.prefs_store
@@ -0,0 +1,1092 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::devices::Capability;
+use crate::drivers;
+use crate::drivers::Error;
+use crate::drivers::{CancelToken, Driver, Phase, Progress, ProgressFn};
+use crate::gpx;
+use crate::logsettings::{LogSettings, WhenFull};
+use crate::serial::SerialPort;
+use crate::tempfile::TempFile;
+use crate::track::{Document, Point, Segment, Time, Track};
+use crate::Format;
+
+/// Size of a flash sector in the log memory.
+const SECTOR_SIZE: usize = 0x10000;
+/// Size of the header at the start of each sector.
+const SECTOR_HEADER_SIZE: usize = 0x200;
+/// Size of the chunks we request from the device.
+const READ_CHUNK_SIZE: usize = 0x400;
+
+/// Time to wait for an answer to a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Erasing the whole flash is slow.
+const ERASE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Log format bits. The fields are stored in a record in that order.
+const LOG_UTC: u32 = 1 << 0;
+const LOG_VALID: u32 = 1 << 1;
+const LOG_LATITUDE: u32 = 1 << 2;
+const LOG_LONGITUDE: u32 = 1 << 3;
+const LOG_HEIGHT: u32 = 1 << 4;
+const LOG_SPEED: u32 = 1 << 5;
+const LOG_HEADING: u32 = 1 << 6;
+const LOG_DSTA: u32 = 1 << 7;
+const LOG_DAGE: u32 = 1 << 8;
+const LOG_PDOP: u32 = 1 << 9;
+const LOG_HDOP: u32 = 1 << 10;
+const LOG_VDOP: u32 = 1 << 11;
+const LOG_NSAT: u32 = 1 << 12;
+const LOG_SID: u32 = 1 << 13;
+const LOG_ELEVATION: u32 = 1 << 14;
+const LOG_AZIMUTH: u32 = 1 << 15;
+const LOG_SNR: u32 = 1 << 16;
+const LOG_RCR: u32 = 1 << 17;
+const LOG_MILLISECOND: u32 = 1 << 18;
+const LOG_DISTANCE: u32 = 1 << 19;
+
+/// The log format bit of the fields, by id.
+const LOG_FIELDS: [(&str, u32); 20] = [
+    ("utc", LOG_UTC),
+    ("valid", LOG_VALID),
+    ("latitude", LOG_LATITUDE),
+    ("longitude", LOG_LONGITUDE),
+    ("height", LOG_HEIGHT),
+    ("speed", LOG_SPEED),
+    ("heading", LOG_HEADING),
+    ("dsta", LOG_DSTA),
+    ("dage", LOG_DAGE),
+    ("pdop", LOG_PDOP),
+    ("hdop", LOG_HDOP),
+    ("vdop", LOG_VDOP),
+    ("nsat", LOG_NSAT),
+    ("sid", LOG_SID),
+    ("elevation", LOG_ELEVATION),
+    ("azimuth", LOG_AZIMUTH),
+    ("snr", LOG_SNR),
+    ("rcr", LOG_RCR),
+    ("millisecond", LOG_MILLISECOND),
+    ("distance", LOG_DISTANCE),
+];
+
+/// Log status bit set when logging.
+const STATUS_LOGGING: u32 = 1 << 1;
+
+// The logger settings, to query with PMTK182,2 and set with PMTK182,1.
+/// The log format, the fields recorded.
+const SETTING_FORMAT: &str = "2";
+/// The time interval, in 1/10 s.
+const SETTING_TIME: &str = "3";
+/// The distance interval, in 1/10 m.
+const SETTING_DISTANCE: &str = "4";
+/// The speed threshold, in 1/10 km/h.
+const SETTING_SPEED: &str = "5";
+/// The recording method: 1 overwrites when full, 2 stops.
+const SETTING_METHOD: &str = "6";
+
+/// VALID field value when there is no fix.
+const VALID_NO_FIX: u16 = 0x0001;
+/// RCR bit for a point logged by pressing the button.
+const RCR_BUTTON: u16 = 0x0008;
+
+/// A decoded log record.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub time: Option<u32>,
+    pub millisecond: u16,
+    pub valid: u16,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub height: Option<f32>,
+    /// Speed in km/h
+    pub speed: Option<f32>,
+    pub heading: Option<f32>,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+    pub satellites: Option<u8>,
+    pub rcr: u16,
+}
+
+impl Record {
+    /// Whether the record has a usable position.
+    fn has_fix(&self) -> bool {
+        self.valid != VALID_NO_FIX && self.latitude.is_some() && self.longitude.is_some()
+    }
+
+    /// Convert to a point.
+    fn to_point(&self) -> Point {
+        let mut point = Point::new(
+            self.latitude.unwrap_or_default(),
+            self.longitude.unwrap_or_default(),
+        );
+        point.ele = self.height.map(f64::from);
+        point.time = self
+            .time
+            .map(|time| Time::from_unix(i64::from(time), u32::from(self.millisecond)));
+        point.course = self.heading.map(f64::from);
+        // GPX speed is in m/s.
+        point.speed = self.speed.map(|speed| f64::from(speed) / 3.6);
+        point.sat = self.satellites.map(u32::from);
+        point.hdop = self.hdop.map(f64::from);
+        point.vdop = self.vdop.map(f64::from);
+        point.pdop = self.pdop.map(f64::from);
+        point
+    }
+}
+
+/// Native driver for MTK based loggers, like the Holux M-241.
+/// It talks the `PMTK182` protocol over the serial port.
+pub struct Mtk {
+    device_id: String,
+    port: String,
+    cap: Capability,
+    serial: Mutex<Option<SerialPort>>,
+}
+
+/// Compute the NMEA checksum of the sentence body (between `$` and `*`).
+fn nmea_checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |acc, c| acc ^ c)
+}
+
+/// Build the full NMEA sentence for the command body.
+fn make_sentence(body: &str) -> String {
+    format!("${}*{:02X}\r\n", body, nmea_checksum(body))
+}
+
+/// Parse a received NMEA sentence. Return the fields if the
+/// checksum is valid.
+fn parse_sentence(line: &str) -> Option<Vec<&str>> {
+    let line = line.trim();
+    if !line.starts_with('$') {
+        return None;
+    }
+    let star = line.rfind('*')?;
+    let body = &line[1..star];
+    let checksum = u8::from_str_radix(&line[star + 1..], 16).ok()?;
+    if nmea_checksum(body) != checksum {
+        return None;
+    }
+    Some(body.split(',').collect())
+}
+
+/// Decode an hexadecimal string into bytes.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_f32(data: &[u8]) -> f32 {
+    f32::from_bits(read_u32(data))
+}
+
+fn read_f64(data: &[u8]) -> f64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    f64::from_le_bytes(bytes)
+}
+
+/// Whether `data` starts with a format separator.
+fn is_separator(data: &[u8]) -> bool {
+    data.len() >= 16
+        && data[..7].iter().all(|&c| c == 0xaa)
+        && data[12..16].iter().all(|&c| c == 0xbb)
+}
+
+/// A cursor to read the fields of a record.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Take the next `size` bytes, if available.
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.pos + size > self.data.len() {
+            return None;
+        }
+        let field = &self.data[self.pos..self.pos + size];
+        self.pos += size;
+        Some(field)
+    }
+}
+
+/// Parse a single record at the start of `data` with the log format `format`.
+/// The Holux M-241 use a more compact encoding for the position.
+/// Return the record and the number of bytes used, or None if it is not valid.
+fn parse_record(data: &[u8], format: u32, holux: bool) -> Option<(Record, usize)> {
+    let mut record = Record::default();
+    let mut c = Cursor { data, pos: 0 };
+
+    if format & LOG_UTC != 0 {
+        record.time = Some(read_u32(c.take(4)?));
+    }
+    if format & LOG_VALID != 0 {
+        record.valid = read_u16(c.take(2)?);
+    }
+    if holux {
+        if format & LOG_LATITUDE != 0 {
+            record.latitude = Some(f64::from(read_f32(c.take(4)?)));
+        }
+        if format & LOG_LONGITUDE != 0 {
+            record.longitude = Some(f64::from(read_f32(c.take(4)?)));
+        }
+        if format & LOG_HEIGHT != 0 {
+            // the height is a float with the low byte stripped.
+            let d = c.take(3)?;
+            record.height = Some(read_f32(&[0, d[0], d[1], d[2]]));
+        }
+    } else {
+        if format & LOG_LATITUDE != 0 {
+            record.latitude = Some(read_f64(c.take(8)?));
+        }
+        if format & LOG_LONGITUDE != 0 {
+            record.longitude = Some(read_f64(c.take(8)?));
+        }
+        if format & LOG_HEIGHT != 0 {
+            record.height = Some(read_f32(c.take(4)?));
+        }
+    }
+    if format & LOG_SPEED != 0 {
+        record.speed = Some(read_f32(c.take(4)?));
+    }
+    if format & LOG_HEADING != 0 {
+        record.heading = Some(read_f32(c.take(4)?));
+    }
+    if format & LOG_DSTA != 0 {
+        c.take(2)?;
+    }
+    if format & LOG_DAGE != 0 {
+        c.take(4)?;
+    }
+    if format & LOG_PDOP != 0 {
+        record.pdop = Some(f32::from(read_u16(c.take(2)?)) / 100.0);
+    }
+    if format & LOG_HDOP != 0 {
+        record.hdop = Some(f32::from(read_u16(c.take(2)?)) / 100.0);
+    }
+    if format & LOG_VDOP != 0 {
+        record.vdop = Some(f32::from(read_u16(c.take(2)?)) / 100.0);
+    }
+    if format & LOG_NSAT != 0 {
+        // in view, in use.
+        record.satellites = Some(c.take(2)?[1]);
+    }
+    if format & LOG_SID != 0 {
+        // Each satellite has its SID and the optional elevation,
+        // azimuth and SNR. The satellite count is in the first SID.
+        let count = read_u16(&c.take(4)?[2..]) as usize;
+        let mut sat_size = 4;
+        for bit in &[LOG_ELEVATION, LOG_AZIMUTH, LOG_SNR] {
+            if format & bit != 0 {
+                sat_size += 2;
+            }
+        }
+        if count > 0 {
+            c.take(count * sat_size - 4)?;
+        }
+    }
+    if format & LOG_RCR != 0 {
+        record.rcr = read_u16(c.take(2)?);
+    }
+    if format & LOG_MILLISECOND != 0 {
+        record.millisecond = read_u16(c.take(2)?);
+    }
+    if format & LOG_DISTANCE != 0 {
+        c.take(8)?;
+    }
+
+    let size = c.pos;
+    if size == 0 {
+        return None;
+    }
+    let checksum = data[..size].iter().fold(0_u8, |acc, c| acc ^ c);
+    if holux {
+        if c.take(1)?[0] != checksum {
+            return None;
+        }
+    } else {
+        let d = c.take(2)?;
+        if d[0] != b'*' || d[1] != checksum {
+            return None;
+        }
+    }
+
+    Some((record, c.pos))
+}
+
+/// Parse the raw log memory into records. Return them with the
+/// addresses of the invalid records, the rest of their sector skipped.
+pub fn parse_log(data: &[u8], holux: bool) -> (Vec<Record>, Vec<usize>) {
+    let mut records = vec![];
+    let mut skipped = vec![];
+    for sector_start in (0..data.len()).step_by(SECTOR_SIZE) {
+        if data.len() < sector_start + SECTOR_HEADER_SIZE {
+            break;
+        }
+        let mut format = read_u32(&data[sector_start + 2..]);
+        if format == 0xffff_ffff {
+            // erased sector. We are done.
+            break;
+        }
+        let end = std::cmp::min(sector_start + SECTOR_SIZE, data.len());
+        let mut i = sector_start + SECTOR_HEADER_SIZE;
+        while i < end {
+            let chunk = &data[i..end];
+            if is_separator(chunk) {
+                // type 2 is a log format change.
+                if chunk[7] == 2 {
+                    format = read_u32(&chunk[8..]);
+                }
+                i += 16;
+                continue;
+            }
+            if chunk.starts_with(b"HOLUXGR241LOGGER") || chunk.starts_with(b"HOLUXGR241WAYPNT") {
+                i += 16;
+                continue;
+            }
+            if chunk.iter().take(16).all(|&c| c == 0xff) {
+                // empty space. End of sector.
+                break;
+            }
+            match parse_record(chunk, format, holux) {
+                Some((record, size)) => {
+                    records.push(record);
+                    i += size;
+                }
+                None => {
+                    eprintln!("Invalid record at 0x{:x}, skipping sector", i);
+                    skipped.push(i);
+                    break;
+                }
+            }
+        }
+    }
+
+    (records, skipped)
+}
+
+/// Convert the records to a document with one track.
+/// Points logged with the button are also waypoints.
+pub fn to_document(records: &[Record]) -> Document {
+    let records: Vec<&Record> = records.iter().filter(|r| r.has_fix()).collect();
+    Document {
+        tracks: vec![Track {
+            name: None,
+            segments: vec![Segment {
+                points: records.iter().map(|r| r.to_point()).collect(),
+            }],
+        }],
+        waypoints: records
+            .iter()
+            .filter(|r| r.rcr & RCR_BUTTON != 0)
+            .map(|r| r.to_point())
+            .collect(),
+    }
+}
+
+impl Mtk {
+    pub fn new(device: String, port: &str, capability: Capability) -> Self {
+        Mtk {
+            device_id: device,
+            port: port.to_owned(),
+            cap: capability,
+            serial: Mutex::new(None),
+        }
+    }
+
+    /// Factory for the driver registry.
+    /// The driver format is the protocol variant, "mtk" or "m241".
+    pub fn create(
+        desc: &drivers::Desc,
+        port: &str,
+        capability: Capability,
+    ) -> Arc<dyn Driver + Send + Sync> {
+        Arc::new(Mtk::new(desc.format().to_string(), port, capability))
+    }
+
+    /// Holux M-241 have their own variant of the protocol.
+    fn is_holux(&self) -> bool {
+        self.device_id == "m241"
+    }
+
+    fn baud_rate(&self) -> u32 {
+        if self.is_holux() {
+            38400
+        } else {
+            115_200
+        }
+    }
+
+    /// Send the command `body`.
+    fn send(serial: &mut SerialPort, body: &str) -> Result<(), Error> {
+        serial
+            .write_line(&make_sentence(body))
+            .map_err(Error::IOError)
+    }
+
+    /// Wait for the sentence starting with the fields `prefix`, for at
+    /// most `timeout` whatever else the device sends.
+    /// Will fail if the device acknowledge the command `command` with an error.
+    fn wait_for(
+        serial: &mut SerialPort,
+        command: &str,
+        prefix: &[&str],
+        timeout: Duration,
+    ) -> Result<Vec<String>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(Error::NotResponding);
+            }
+            let line = serial
+                .read_line(remaining)
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::TimedOut => Error::NotResponding,
+                    _ => Error::IOError(err),
+                })?;
+            let fields = match parse_sentence(&line) {
+                Some(fields) => fields,
+                None => continue,
+            };
+            if fields.starts_with(prefix) {
+                return Ok(fields.iter().map(|s| s.to_string()).collect());
+            }
+            // $PMTK001,182,<command>,<flag>. 3 is success.
+            if fields.len() >= 4
+                && fields[0] == "PMTK001"
+                && fields[1] == "182"
+                && fields[2] == command
+                && fields[3] != "3"
+            {
+                return Err(Error::Protocol(format!(
+                    "Device rejected command {}: {}",
+                    command, fields[3]
+                )));
+            }
+        }
+    }
+
+    /// Query the value `param` of the logger.
+    fn query(serial: &mut SerialPort, param: &str) -> Result<String, Error> {
+        Self::send(serial, &format!("PMTK182,2,{}", param))?;
+        let fields = Self::wait_for(serial, "2", &["PMTK182", "3", param], COMMAND_TIMEOUT)?;
+        fields
+            .get(3)
+            .cloned()
+            .ok_or_else(|| Error::Protocol("Invalid answer".to_string()))
+    }
+
+    /// Read `len` bytes of the log memory at `address`.
+    fn read_memory(serial: &mut SerialPort, address: usize, len: usize) -> Result<Vec<u8>, Error> {
+        Self::send(serial, &format!("PMTK182,7,{:08X},{:08X}", address, len))?;
+        let mut data = vec![];
+        while data.len() < len {
+            let fields = Self::wait_for(serial, "7", &["PMTK182", "8"], COMMAND_TIMEOUT)?;
+            if fields.len() < 4 {
+                return Err(Error::Protocol("Invalid data".to_string()));
+            }
+            let chunk_address = usize::from_str_radix(&fields[2], 16)
+                .map_err(|_| Error::Protocol("Invalid address".to_string()))?;
+            if chunk_address != address + data.len() {
+                return Err(Error::Protocol(format!(
+                    "Unexpected address 0x{:x}",
+                    chunk_address
+                )));
+            }
+            let mut chunk = decode_hex(&fields[3])
+                .ok_or_else(|| Error::Protocol("Invalid data".to_string()))?;
+            data.append(&mut chunk);
+        }
+        Self::wait_for(serial, "7", &["PMTK001", "182", "7", "3"], COMMAND_TIMEOUT)?;
+        Ok(data)
+    }
+
+    /// Read the whole used log memory.
+    fn read_log(
+        serial: &mut SerialPort,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<Vec<u8>, Error> {
+        progress(Progress::new(Phase::Connecting, 0, None));
+        // 8 is the next write address, ie the end of the used memory.
+        let end = usize::from_str_radix(&Self::query(serial, "8")?, 16)
+            .map_err(|_| Error::Protocol("Invalid memory size".to_string()))?;
+        let mut data = Vec::with_capacity(end);
+        let mut address = 0;
+        while address < end {
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            progress(Progress::new(
+                Phase::Downloading,
+                address as u64,
+                Some(end as u64),
+            ));
+            let len = std::cmp::min(READ_CHUNK_SIZE, end - address);
+            let mut chunk = Self::read_memory(serial, address, len)?;
+            data.append(&mut chunk);
+            address += len;
+        }
+        Ok(data)
+    }
+
+    /// The number of records in the log, including those without a fix.
+    fn record_count(serial: &mut SerialPort) -> Result<u64, Error> {
+        // 10 is the number of records.
+        u64::from_str_radix(&Self::query(serial, "10")?, 16)
+            .map_err(|_| Error::Protocol("Invalid record count".to_string()))
+    }
+
+    /// Check that the `read` records are all those `logged`. Those
+    /// without a fix are counted: they aren't in the track. Any record
+    /// `skipped` as invalid is an error, as its sector was lost.
+    fn check_records(read: usize, logged: u64, skipped: &[usize]) -> Result<(), Error> {
+        if !skipped.is_empty() {
+            let addresses: Vec<String> = skipped
+                .iter()
+                .map(|address| format!("0x{:x}", address))
+                .collect();
+            return Err(Error::Failed(format!(
+                "The log has invalid records at {}.",
+                addresses.join(", ")
+            )));
+        }
+        if (read as u64) < logged {
+            return Err(Error::Failed(format!(
+                "Only {} of the {} records logged were read.",
+                read, logged
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the log `status`, in hex, is logging.
+    fn is_logging(status: &str) -> Result<bool, Error> {
+        u32::from_str_radix(status, 16)
+            .map(|status| status & STATUS_LOGGING != 0)
+            .map_err(|_| Error::Protocol("Invalid log status".to_string()))
+    }
+
+    /// The log format with the fields `fields`.
+    fn fields_to_format(fields: &[String]) -> Result<u32, Error> {
+        fields.iter().try_fold(0, |format, id| {
+            LOG_FIELDS
+                .iter()
+                .find(|(field, _)| field == id)
+                .map(|(_, bit)| format | bit)
+                .ok_or_else(|| Error::Failed(format!("Unknown field {}", id)))
+        })
+    }
+
+    /// The fields in the log `format`.
+    fn format_to_fields(format: u32) -> Vec<String> {
+        LOG_FIELDS
+            .iter()
+            .filter(|(_, bit)| format & bit != 0)
+            .map(|(field, _)| field.to_string())
+            .collect()
+    }
+
+    /// Query the setting `param`, unless `cancel` is cancelled.
+    fn query_setting(
+        serial: &mut SerialPort,
+        param: &str,
+        cancel: &CancelToken,
+    ) -> Result<String, Error> {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Self::query(serial, param)
+    }
+
+    /// Query the setting `param`, in tenths.
+    fn query_tenths(
+        serial: &mut SerialPort,
+        param: &str,
+        cancel: &CancelToken,
+    ) -> Result<f64, Error> {
+        Self::query_setting(serial, param, cancel)?
+            .parse::<u32>()
+            .map(|value| f64::from(value) / 10.0)
+            .map_err(|_| Error::Protocol("Invalid setting".to_string()))
+    }
+
+    /// Set the logger setting `param` to `value`, unless `cancel` is
+    /// cancelled.
+    fn set_setting(
+        serial: &mut SerialPort,
+        param: &str,
+        value: &str,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Self::send(serial, &format!("PMTK182,1,{},{}", param, value))?;
+        Self::wait_for(serial, "1", &["PMTK001", "182", "1", "3"], COMMAND_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn erase_memory(serial: &mut SerialPort, progress: &ProgressFn) -> Result<(), Error> {
+        progress(Progress::new(Phase::Erasing, 0, None));
+        Self::send(serial, "PMTK182,6,1")?;
+        Self::wait_for(serial, "6", &["PMTK001", "182", "6", "3"], ERASE_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+impl Driver for Mtk {
+    fn open(&mut self) -> Result<(), Error> {
+        if self.port.is_empty() {
+            return Err(Error::Failed("No port is set.".to_string()));
+        }
+        let serial = SerialPort::open(Path::new(&self.port), self.baud_rate())
+            .map_err(|err| Error::from_port_error(err, &self.port))?;
+        *self.serial.get_mut().unwrap() = Some(serial);
+        Ok(())
+    }
+
+    fn close(&mut self) -> bool {
+        *self.serial.get_mut().unwrap() = None;
+        true
+    }
+
+    /// Download the data into a temporary file, deleted when dropped.
+    fn download(
+        &self,
+        format: Format,
+        erase: bool,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<TempFile, Error> {
+        if erase && !self.cap.can_erase {
+            return Err(Error::Unsupported);
+        }
+        match format {
+            Format::Gpx => {}
+            Format::None => return Err(Error::WrongArg),
+            _ => return Err(Error::Unsupported),
+        }
+
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        let logged = Self::record_count(serial)?;
+        let data = Self::read_log(serial, progress, cancel)?;
+        progress(Progress::new(Phase::Writing, 0, None));
+        let (records, skipped) = parse_log(&data, self.is_holux());
+        Self::check_records(records.len(), logged, &skipped)?;
+
+        let temp = TempFile::new("gpsami.gpx").map_err(Error::IOError)?;
+        let file = File::create(temp.path()).map_err(Error::IOError)?;
+        let mut out = BufWriter::new(file);
+        gpx::write(&mut out, &to_document(&records), gpx::Version::Gpx10)
+            .and_then(|_| out.flush())
+            .map_err(Error::IOError)?;
+
+        if erase {
+            // Once started, erasing can't be interrupted.
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            Self::erase_memory(serial, progress)?;
+        }
+        Ok(temp)
+    }
+
+    /// Erase the logs on the device. Return an error if not capable.
+    fn erase(&self, progress: &ProgressFn, cancel: &CancelToken) -> Result<(), Error> {
+        if !self.cap.can_erase_only {
+            return Err(Error::Unsupported);
+        }
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Self::erase_memory(serial, progress)
+    }
+
+    /// Start or stop logging. Return an error if not capable.
+    fn set_logging(
+        &self,
+        enable: bool,
+        progress: &ProgressFn,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        if !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
+        }
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        progress(Progress::new(Phase::Connecting, 0, None));
+        // 4 starts logging, 5 stops it.
+        let command = if enable { "4" } else { "5" };
+        Self::send(serial, &format!("PMTK182,{}", command))?;
+        Self::wait_for(
+            serial,
+            command,
+            &["PMTK001", "182", command, "3"],
+            COMMAND_TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    fn logging(&self) -> Result<bool, Error> {
+        if !self.cap.can_log_enable {
+            return Err(Error::Unsupported);
+        }
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        // 7 is the log status.
+        Self::is_logging(&Self::query(serial, "7")?)
+    }
+
+    fn log_settings(&self, cancel: &CancelToken) -> Result<LogSettings, Error> {
+        if !self.cap.can_configure() {
+            return Err(Error::Unsupported);
+        }
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        let format = u32::from_str_radix(&Self::query_setting(serial, SETTING_FORMAT, cancel)?, 16)
+            .map_err(|_| Error::Protocol("Invalid log format".to_string()))?;
+        let when_full = match Self::query_setting(serial, SETTING_METHOD, cancel)?.as_str() {
+            "1" => WhenFull::Overwrite,
+            "2" => WhenFull::Stop,
+            _ => return Err(Error::Protocol("Invalid recording method".to_string())),
+        };
+        Ok(LogSettings {
+            time_interval: Self::query_tenths(serial, SETTING_TIME, cancel)?,
+            distance_interval: Self::query_tenths(serial, SETTING_DISTANCE, cancel)?,
+            speed_threshold: Self::query_tenths(serial, SETTING_SPEED, cancel)?,
+            fields: Self::format_to_fields(format),
+            when_full,
+        })
+    }
+
+    fn set_log_settings(&self, settings: &LogSettings, cancel: &CancelToken) -> Result<(), Error> {
+        if !self.cap.can_configure() {
+            return Err(Error::Unsupported);
+        }
+        let format = Self::fields_to_format(&settings.fields)?;
+        let tenths = |value: f64| format!("{}", (value * 10.0).round() as u32);
+        let method = match settings.when_full {
+            WhenFull::Overwrite => "1",
+            WhenFull::Stop => "2",
+        };
+        let mut guard = self.serial.lock().unwrap();
+        let serial = guard
+            .as_mut()
+            .ok_or_else(|| Error::Failed("Device not open".to_string()))?;
+        let values = vec![
+            (SETTING_FORMAT, format!("{:08X}", format)),
+            (SETTING_TIME, tenths(settings.time_interval)),
+            (SETTING_DISTANCE, tenths(settings.distance_interval)),
+            (SETTING_SPEED, tenths(settings.speed_threshold)),
+            (SETTING_METHOD, method.to_string()),
+        ];
+        for (param, value) in values {
+            Self::set_setting(serial, param, &value, cancel)?;
+        }
+        Ok(())
+    }
+}
+
+/// A pseudo terminal for a fake device: the master side, and the path
+/// of the slave side to open as the port.
+#[cfg(test)]
+fn fake_port() -> (File, String) {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    assert!(master >= 0);
+    assert_eq!(unsafe { libc::grantpt(master) }, 0);
+    assert_eq!(unsafe { libc::unlockpt(master) }, 0);
+    let slave = unsafe { CStr::from_ptr(libc::ptsname(master)) }
+        .to_string_lossy()
+        .into_owned();
+    (unsafe { File::from_raw_fd(master) }, slave)
+}
+
+/// A log memory with one record with a fix.
+#[cfg(test)]
+fn fake_log() -> Vec<u8> {
+    let format = LOG_UTC | LOG_VALID | LOG_LATITUDE | LOG_LONGITUDE | LOG_HEIGHT;
+    let mut data = vec![0xff_u8; SECTOR_HEADER_SIZE];
+    data[0..2].copy_from_slice(&1_u16.to_le_bytes());
+    data[2..6].copy_from_slice(&format.to_le_bytes());
+
+    let mut record = vec![];
+    record.extend_from_slice(&1_582_416_000_u32.to_le_bytes());
+    record.extend_from_slice(&2_u16.to_le_bytes());
+    record.extend_from_slice(&45.5_f64.to_le_bytes());
+    record.extend_from_slice(&(-73.5_f64).to_le_bytes());
+    record.extend_from_slice(&30.0_f32.to_le_bytes());
+    let checksum = record.iter().fold(0_u8, |acc, c| acc ^ c);
+    data.append(&mut record);
+    data.push(b'*');
+    data.push(checksum);
+    data.extend_from_slice(&[0xff; 32]);
+    data
+}
+
+#[test]
+fn test_wait_for_deadline() {
+    use std::thread;
+
+    // The device streaming NMEA on the master side.
+    let (mut device, slave) = fake_port();
+    let mut serial = SerialPort::open(Path::new(&slave), 115_200).unwrap();
+    let streaming = thread::spawn(move || {
+        let sentence = make_sentence("GPGGA,,,,,,0,00,,,M,,M,,");
+        for _ in 0..20 {
+            if device.write_all(sentence.as_bytes()).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    let start = Instant::now();
+    let result = Mtk::wait_for(
+        &mut serial,
+        "2",
+        &["PMTK182", "3"],
+        Duration::from_millis(300),
+    );
+    assert!(matches!(result, Err(Error::NotResponding)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(serial);
+    streaming.join().unwrap();
+}
+
+#[test]
+fn test_check_records() {
+    assert!(Mtk::check_records(10, 10, &[]).is_ok());
+    // Logged while downloading.
+    assert!(Mtk::check_records(10, 9, &[]).is_ok());
+    assert_eq!(
+        Mtk::check_records(9, 10, &[]).unwrap_err().to_string(),
+        "Only 9 of the 10 records logged were read."
+    );
+    assert_eq!(
+        Mtk::check_records(10, 10, &[0x200, 0x10200])
+            .unwrap_err()
+            .to_string(),
+        "The log has invalid records at 0x200, 0x10200."
+    );
+}
+
+#[test]
+fn test_download_checks_records() {
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    let capability: Capability = rustc_serialize::json::decode(
+        r#"{ "can_erase": true, "can_erase_only": true,
+             "can_log_enable": false, "can_shutoff": false }"#,
+    )
+    .unwrap();
+    // The device answers with the log, reporting `logged` records.
+    // Return whether it was erased.
+    let download = |logged: u64| {
+        let (master, slave) = fake_port();
+        let mut device = master.try_clone().unwrap();
+        let answering = thread::spawn(move || {
+            let log = fake_log();
+            let mut erased = false;
+            for line in BufReader::new(master).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let fields = match parse_sentence(&line) {
+                    Some(fields) => fields,
+                    None => continue,
+                };
+                let answers = match fields[..] {
+                    ["PMTK182", "2", "10"] => vec![format!("PMTK182,3,10,{:08X}", logged)],
+                    ["PMTK182", "2", "8"] => vec![format!("PMTK182,3,8,{:08X}", log.len())],
+                    ["PMTK182", "7", address, len] => {
+                        let address = usize::from_str_radix(address, 16).unwrap();
+                        let len = usize::from_str_radix(len, 16).unwrap();
+                        let data: String = log[address..address + len]
+                            .iter()
+                            .map(|c| format!("{:02X}", c))
+                            .collect();
+                        vec![
+                            format!("PMTK182,8,{:08X},{}", address, data),
+                            "PMTK001,182,7,3".to_string(),
+                        ]
+                    }
+                    ["PMTK182", "6", "1"] => {
+                        erased = true;
+                        vec!["PMTK001,182,6,3".to_string()]
+                    }
+                    _ => vec![],
+                };
+                for answer in answers {
+                    device.write_all(make_sentence(&answer).as_bytes()).unwrap();
+                }
+            }
+            erased
+        });
+
+        let mut mtk = Mtk::new("mtk".to_string(), &slave, capability.clone());
+        mtk.open().unwrap();
+        let result = mtk.download(Format::Gpx, true, &|_| {}, &CancelToken::new());
+        drop(mtk);
+        (result, answering.join().unwrap())
+    };
+
+    let (result, erased) = download(1);
+    assert!(result.is_ok());
+    assert!(erased);
+
+    // A record is missing: not erased.
+    let (result, erased) = download(2);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Only 1 of the 2 records logged were read."
+    );
+    assert!(!erased);
+}
+
+#[test]
+fn test_is_logging() {
+    assert!(Mtk::is_logging("2").unwrap());
+    assert!(Mtk::is_logging("106").unwrap());
+    assert!(!Mtk::is_logging("104").unwrap());
+    assert!(Mtk::is_logging("xyz").is_err());
+}
+
+#[test]
+fn test_log_fields() {
+    use crate::logsettings::FIELDS;
+
+    // All the known fields are in the log format.
+    for (id, _) in FIELDS {
+        assert!(LOG_FIELDS.iter().any(|(field, _)| field == id));
+    }
+    let fields = vec![
+        "utc".to_string(),
+        "latitude".to_string(),
+        "longitude".to_string(),
+    ];
+    let format = Mtk::fields_to_format(&fields).unwrap();
+    assert_eq!(format, 0x0d);
+    assert_eq!(Mtk::format_to_fields(format), fields);
+    assert!(Mtk::fields_to_format(&["altitude".to_string()]).is_err());
+}
+
+#[test]
+fn test_sentence() {
+    assert_eq!(make_sentence("PMTK182,6,1"), "$PMTK182,6,1*3E\r\n");
+
+    let fields = parse_sentence("$PMTK001,182,7,3*20\r\n");
+    assert_eq!(fields, Some(vec!["PMTK001", "182", "7", "3"]));
+    // bad checksum
+    assert!(parse_sentence("$PMTK001,182,7,3*21").is_none());
+    assert!(parse_sentence("PMTK001,182,7,3").is_none());
+}
+
+#[test]
+fn test_decode_hex() {
+    assert_eq!(decode_hex("00A1ff"), Some(vec![0x00, 0xa1, 0xff]));
+    assert!(decode_hex("0").is_none());
+    assert!(decode_hex("zz").is_none());
+}
+
+#[test]
+fn test_parse_log() {
+    let mut data = fake_log();
+
+    let (records, skipped) = parse_log(&data, false);
+    assert_eq!(records.len(), 1);
+    assert!(skipped.is_empty());
+    assert_eq!(records[0].time, Some(1_582_416_000));
+    assert_eq!(records[0].latitude, Some(45.5));
+    assert_eq!(records[0].longitude, Some(-73.5));
+    assert_eq!(records[0].height, Some(30.0));
+    assert!(records[0].has_fix());
+
+    // corrupt the checksum
+    let len = data.len();
+    data[len - 33] ^= 0xff;
+    let (records, skipped) = parse_log(&data, false);
+    assert!(records.is_empty());
+    assert_eq!(skipped, vec![SECTOR_HEADER_SIZE]);
+}
+
+#[test]
+fn test_to_document() {
+    let mut record = Record {
+        time: Some(1_582_416_000),
+        millisecond: 500,
+        valid: 2,
+        latitude: Some(45.5),
+        longitude: Some(-73.5),
+        speed: Some(36.0),
+        ..Record::default()
+    };
+    let no_fix = Record {
+        valid: VALID_NO_FIX,
+        ..record.clone()
+    };
+    let mut button = record.clone();
+    button.rcr = RCR_BUTTON;
+    record.rcr = 1;
+
+    let document = to_document(&[record, no_fix, button]);
+    assert_eq!(document.waypoints.len(), 1);
+    assert_eq!(document.tracks.len(), 1);
+    let points = &document.tracks[0].segments[0].points;
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].time, Some(Time::from_unix(1_582_416_000, 500)));
+    assert_eq!(points[0].speed, Some(10.0));
+}
@@ -0,0 +1,232 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Check whether the user can use a serial port.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// The access to a port.
+#[derive(Clone, Debug, Default, PartialEq, RustcEncodable)]
+pub struct PortAccess {
+    /// The port can be read and written.
+    pub allowed: bool,
+    /// The group owning the port.
+    pub group: Option<String>,
+    /// The user is a member of `group`.
+    pub member: bool,
+    /// The membership is in effect. It isn't until the user logs in
+    /// again after being added to the group.
+    pub member_active: bool,
+    /// The programs that have the port open. Only the processes the
+    /// user can see are found.
+    pub held_by: Vec<String>,
+}
+
+impl PortAccess {
+    /// Check the access to the port at `path`.
+    pub fn check(path: &Path) -> PortAccess {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return PortAccess::default(),
+        };
+        let gid = metadata.gid();
+        let (group, members) = match group(gid) {
+            Some((name, members)) => (Some(name), members),
+            None => (None, vec![]),
+        };
+        let user = user();
+        let member = user
+            .map(|(name, user_gid)| user_gid == gid || members.contains(&name))
+            .unwrap_or(false);
+        PortAccess {
+            allowed: is_allowed(path),
+            group,
+            member,
+            member_active: process_groups().contains(&gid),
+            held_by: holders(path, Path::new("/proc")),
+        }
+    }
+
+    /// A short description of the problem with the port, if any.
+    pub fn problem(&self) -> Option<String> {
+        if !self.allowed {
+            return Some(match (&self.group, self.member, self.member_active) {
+                (Some(group), false, _) => format!("not allowed, join the group {}", group),
+                (Some(group), true, false) => {
+                    format!("not allowed, log in again to be in {}", group)
+                }
+                _ => "not allowed".to_string(),
+            });
+        }
+        if !self.held_by.is_empty() {
+            return Some(format!("in use by {}", self.held_by.join(", ")));
+        }
+        None
+    }
+}
+
+/// Whether the user can read and write `path`.
+fn is_allowed(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// The name and the members of the group `gid`.
+fn group(gid: libc::gid_t) -> Option<(String, Vec<String>)> {
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let err =
+        unsafe { libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if err != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(group.gr_name) }
+        .to_string_lossy()
+        .into_owned();
+    let mut members = vec![];
+    let mut member = group.gr_mem;
+    while !member.is_null() && !unsafe { *member }.is_null() {
+        members.push(
+            unsafe { CStr::from_ptr(*member) }
+                .to_string_lossy()
+                .into_owned(),
+        );
+        member = unsafe { member.add(1) };
+    }
+    Some((name, members))
+}
+
+/// The name and the primary group of the user.
+fn user() -> Option<(String, libc::gid_t)> {
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let err = unsafe {
+        libc::getpwuid_r(
+            libc::getuid(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if err != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) }
+        .to_string_lossy()
+        .into_owned();
+    Some((name, passwd.pw_gid))
+}
+
+/// The groups the process is in.
+fn process_groups() -> Vec<libc::gid_t> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+    groups
+}
+
+/// The names of the other processes having `path` open, looking in
+/// `proc_dir`.
+fn holders(path: &Path, proc_dir: &Path) -> Vec<String> {
+    let own_pid = std::process::id().to_string();
+    let mut holders: Vec<String> = vec![];
+    let entries = match fs::read_dir(proc_dir) {
+        Ok(entries) => entries,
+        Err(_) => return holders,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if pid == own_pid || !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        // Not allowed for the processes of the other users.
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds = fds.filter_map(|fd| fd.ok()).any(|fd| {
+            fs::read_link(fd.path())
+                .map(|target| target == path)
+                .unwrap_or(false)
+        });
+        if holds {
+            let name = fs::read_to_string(entry.path().join("comm"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or(pid);
+            if !holders.contains(&name) {
+                holders.push(name);
+            }
+        }
+    }
+    holders
+}
+
+#[test]
+fn test_problem() {
+    let mut access = PortAccess {
+        allowed: true,
+        group: Some("dialout".to_string()),
+        ..PortAccess::default()
+    };
+    assert_eq!(access.problem(), None);
+    access.held_by = vec!["gpsd".to_string()];
+    assert_eq!(access.problem(), Some("in use by gpsd".to_string()));
+
+    access.allowed = false;
+    assert_eq!(
+        access.problem(),
+        Some("not allowed, join the group dialout".to_string())
+    );
+    access.member = true;
+    assert_eq!(
+        access.problem(),
+        Some("not allowed, log in again to be in dialout".to_string())
+    );
+    access.member_active = true;
+    assert_eq!(access.problem(), Some("not allowed".to_string()));
+}
+
+#[test]
+fn test_holders() {
+    use crate::tempfile::TempFile;
+    use std::os::unix::fs::symlink;
+
+    let proc_dir = TempFile::new("proc").unwrap();
+    let proc_dir = proc_dir.path();
+    let port = Path::new("/dev/ttyUSB0");
+    let add_process = |pid: &str, name: &str, target: &Path| {
+        let fd_dir = proc_dir.join(pid).join("fd");
+        fs::create_dir_all(&fd_dir).unwrap();
+        fs::write(proc_dir.join(pid).join("comm"), format!("{}\n", name)).unwrap();
+        symlink(target, fd_dir.join("3")).unwrap();
+    };
+    add_process("100", "gpsd", port);
+    add_process("200", "bash", Path::new("/dev/pts/0"));
+    add_process(&std::process::id().to_string(), "gpsami", port);
+    fs::create_dir_all(proc_dir.join("self")).unwrap();
+
+    assert_eq!(holders(port, proc_dir), vec!["gpsd".to_string()]);
+    assert!(holders(Path::new("/dev/ttyUSB1"), proc_dir).is_empty());
+    assert!(holders(port, &proc_dir.join("none")).is_empty());
+}
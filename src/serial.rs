@@ -0,0 +1,103 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A raw serial port, used for the native drivers.
+pub struct SerialPort {
+    file: File,
+    buffer: Vec<u8>,
+}
+
+/// Map the baud rate to the termios speed constant.
+fn baud_to_speed(baud: u32) -> Option<libc::speed_t> {
+    match baud {
+        4800 => Some(libc::B4800),
+        9600 => Some(libc::B9600),
+        19200 => Some(libc::B19200),
+        38400 => Some(libc::B38400),
+        57600 => Some(libc::B57600),
+        115_200 => Some(libc::B115200),
+        _ => None,
+    }
+}
+
+impl SerialPort {
+    /// Open the serial port at `path` in raw mode, 8N1 at `baud`.
+    pub fn open(path: &Path, baud: u32) -> io::Result<Self> {
+        let speed = baud_to_speed(baud)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unsupported baud rate"))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+        unsafe {
+            let mut tio: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut tio);
+            tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+            // Non blocking read with a 100ms inter-byte timeout.
+            tio.c_cc[libc::VMIN] = 0;
+            tio.c_cc[libc::VTIME] = 1;
+            if libc::cfsetispeed(&mut tio, speed) != 0 || libc::cfsetospeed(&mut tio, speed) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::tcflush(fd, libc::TCIOFLUSH);
+        }
+
+        Ok(SerialPort {
+            file,
+            buffer: vec![],
+        })
+    }
+
+    /// Write a line. The caller is responsible for the line terminator.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+
+    /// Read a line, without the terminator. Will return an error
+    /// of kind `TimedOut` if nothing complete came within `timeout`.
+    pub fn read_line(&mut self, timeout: Duration) -> io::Result<String> {
+        let start = Instant::now();
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                return Ok(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            if start.elapsed() > timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "serial read timeout",
+                ));
+            }
+            let mut buf = [0_u8; 512];
+            let count = self.file.read(&mut buf)?;
+            self.buffer.extend_from_slice(&buf[..count]);
+        }
+    }
+}
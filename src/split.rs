@@ -0,0 +1,341 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Split the downloaded data into one file per day or per trip.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::drivers::Error;
+use crate::gpx;
+use crate::tempfile::TempFile;
+use crate::template::{self, Template, Values};
+use crate::track::{Document, Point, Segment, Time, Track};
+
+/// The default gap between two trips, in seconds.
+pub const DEFAULT_TRIP_GAP: i64 = 3600;
+/// The default file name template per day.
+pub const DEFAULT_DAY_TEMPLATE: &str = "{model}-{first_point_time:%Y-%m-%d}.{ext}";
+/// The default file name template per trip.
+pub const DEFAULT_TRIP_TEMPLATE: &str = "{model}-{first_point_time}.{ext}";
+
+/// How to split the data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Everything in one file.
+    None,
+    /// One file per calendar day.
+    Day,
+    /// A new file when there is a gap in time.
+    Trip,
+}
+
+impl Mode {
+    /// The mode for the id in the settings.
+    pub fn from_id(id: &str) -> Option<Mode> {
+        match id {
+            "none" => Some(Mode::None),
+            "day" => Some(Mode::Day),
+            "trip" => Some(Mode::Trip),
+            _ => None,
+        }
+    }
+
+    /// The default file name template.
+    pub fn default_template(self) -> &'static str {
+        match self {
+            Mode::Trip => DEFAULT_TRIP_TEMPLATE,
+            _ => DEFAULT_DAY_TEMPLATE,
+        }
+    }
+}
+
+/// A part of the data, saved in its own file.
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub document: Document,
+}
+
+/// A part being built, keeping the structure in tracks and segments.
+#[derive(Default)]
+struct PartBuilder {
+    document: Document,
+    /// The track and segment index in the source of the last point.
+    last: Option<(usize, usize)>,
+}
+
+impl PartBuilder {
+    fn push(&mut self, source: &Document, track: usize, segment: usize, point: &Point) {
+        match self.last {
+            Some((t, s)) if t == track && s == segment => {}
+            Some((t, _)) if t == track => {
+                self.document
+                    .tracks
+                    .last_mut()
+                    .unwrap()
+                    .segments
+                    .push(Segment::default());
+            }
+            _ => {
+                self.document.tracks.push(Track {
+                    name: source.tracks[track].name.clone(),
+                    segments: vec![Segment::default()],
+                });
+            }
+        }
+        self.last = Some((track, segment));
+        let track = self.document.tracks.last_mut().unwrap();
+        track
+            .segments
+            .last_mut()
+            .unwrap()
+            .points
+            .push(point.clone());
+    }
+}
+
+/// Split the data and save it into files.
+pub struct Splitter {
+    pub mode: Mode,
+    /// The minimum gap between two trips, in seconds.
+    pub trip_gap: i64,
+    /// The file name template. Its timezone is the one of the days.
+    pub template: Template,
+}
+
+impl Splitter {
+    /// The local day of `time`, in days since the epoch.
+    fn local_day(&self, time: Time) -> i64 {
+        (time.unix() + self.template.offset(time)).div_euclid(86400)
+    }
+
+    /// Split `document` into parts, in time order. Points without a
+    /// time go with the previous ones.
+    pub fn split(&self, document: &Document) -> Vec<Part> {
+        let first_time = document.track_points().find_map(|point| point.time);
+        let mut parts: BTreeMap<i64, PartBuilder> = BTreeMap::new();
+        // The start time of each trip, to place the waypoints.
+        let mut trips: Vec<(Time, i64)> = vec![];
+        let mut key = match (self.mode, first_time) {
+            (Mode::Day, Some(time)) => self.local_day(time),
+            _ => 0,
+        };
+        let mut last_time: Option<Time> = None;
+        for (t, track) in document.tracks.iter().enumerate() {
+            for (s, segment) in track.segments.iter().enumerate() {
+                for point in &segment.points {
+                    if let Some(time) = point.time {
+                        match self.mode {
+                            Mode::None => {}
+                            Mode::Day => key = self.local_day(time),
+                            Mode::Trip => {
+                                let gap = last_time.map(|last| (time.unix() - last.unix()).abs());
+                                if gap.map(|gap| gap > self.trip_gap).unwrap_or(false) {
+                                    key += 1;
+                                }
+                                if trips.last().map(|&(_, k)| k) != Some(key) {
+                                    trips.push((time, key));
+                                }
+                            }
+                        }
+                        last_time = Some(time);
+                    }
+                    parts.entry(key).or_default().push(document, t, s, point);
+                }
+            }
+        }
+
+        for waypoint in &document.waypoints {
+            let key = match (self.mode, waypoint.time) {
+                (Mode::Day, Some(time)) => self.local_day(time),
+                (Mode::Trip, Some(time)) => trips
+                    .iter()
+                    .rev()
+                    .find(|&&(start, _)| start <= time)
+                    .or_else(|| trips.first())
+                    .map(|&(_, key)| key)
+                    .unwrap_or(0),
+                _ => parts.keys().next().cloned().unwrap_or(key),
+            };
+            parts
+                .entry(key)
+                .or_default()
+                .document
+                .waypoints
+                .push(waypoint.clone());
+        }
+
+        parts
+            .into_values()
+            .map(|builder| Part {
+                document: builder.document,
+            })
+            .collect()
+    }
+
+    /// The file name for `part`, the `index`th, starting at 1,
+    /// downloaded at `date`.
+    pub fn file_name(&self, model: &str, part: &Part, index: usize, date: Time) -> String {
+        let time_span = part.document.time_span();
+        self.template.expand(&Values {
+            model,
+            date,
+            first_point_time: time_span.map(|(start, _)| start),
+            last_point_time: time_span.map(|(_, end)| end),
+            index: Some(index),
+            // The parts are always GPX.
+            extension: "gpx",
+        })
+    }
+
+    /// Split the GPX file `source` and save the parts into `dir`.
+    /// Existing files aren't overwritten. Return the files written.
+    pub fn save(&self, source: &Path, dir: &Path, model: &str) -> Result<Vec<PathBuf>, Error> {
+        let content = fs::read_to_string(source).map_err(Error::IOError)?;
+        let document = gpx::read(&content)
+            .map_err(|err| Error::Failed(format!("Can't split the download: {}", err)))?;
+        let date = template::now();
+        let mut outputs = vec![];
+        for (index, part) in self.split(&document).iter().enumerate() {
+            let output = template::unique_path(dir, &self.file_name(model, part, index + 1, date));
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent).map_err(Error::IOError)?;
+            }
+            let temp = TempFile::new("part.gpx").map_err(Error::IOError)?;
+            let file = File::create(temp.path()).map_err(Error::IOError)?;
+            let mut out = BufWriter::new(file);
+            gpx::write(&mut out, &part.document, gpx::Version::Gpx10)
+                .and_then(|_| out.flush())
+                .map_err(Error::IOError)?;
+            temp.persist(&output).map_err(Error::IOError)?;
+            outputs.push(output);
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+fn make_document(times: &[&str]) -> Document {
+    let points = times
+        .iter()
+        .map(|time| {
+            let mut point = Point::new(45.5, -73.5);
+            point.time = Time::parse(time);
+            point
+        })
+        .collect();
+    Document {
+        tracks: vec![Track {
+            name: Some("track".to_string()),
+            segments: vec![Segment { points }],
+        }],
+        waypoints: vec![],
+    }
+}
+
+#[test]
+fn test_split_day() {
+    use std::sync::Arc;
+
+    let now = Time::parse("2019-06-01T00:00:00Z").unwrap();
+    let mut document = make_document(&[
+        "2019-05-04T12:00:00Z",
+        "2019-05-04T23:00:00Z",
+        "2019-05-05T01:00:00Z",
+        "2019-05-05T06:00:00Z",
+    ]);
+    let mut waypoint = Point::new(45.5, -73.5);
+    waypoint.time = Time::parse("2019-05-05T04:00:00Z");
+    document.waypoints.push(waypoint);
+
+    let utc = Splitter {
+        mode: Mode::Day,
+        trip_gap: DEFAULT_TRIP_GAP,
+        template: Template::new(DEFAULT_DAY_TEMPLATE, template::utc()),
+    };
+    let parts = utc.split(&document);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].document.track_points().count(), 2);
+    assert_eq!(parts[1].document.track_points().count(), 2);
+    assert_eq!(parts[1].document.waypoints.len(), 1);
+    assert_eq!(parts[0].document.tracks[0].name, Some("track".to_string()));
+    assert_eq!(
+        utc.file_name("m241", &parts[1], 2, now),
+        "m241-2019-05-05.gpx"
+    );
+
+    // 5 hours behind UTC, the waypoint at 04:00Z is the evening before.
+    let est = Splitter {
+        template: Template::new(DEFAULT_DAY_TEMPLATE, Arc::new(|_| -5 * 3600)),
+        ..utc
+    };
+    let parts = est.split(&document);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].document.track_points().count(), 3);
+    assert_eq!(parts[1].document.track_points().count(), 1);
+    assert_eq!(parts[0].document.waypoints.len(), 1);
+    assert_eq!(
+        est.file_name("m241", &parts[0], 1, now),
+        "m241-2019-05-04.gpx"
+    );
+}
+
+#[test]
+fn test_split_trip() {
+    use std::sync::Arc;
+
+    let now = Time::parse("2019-06-01T00:00:00Z").unwrap();
+    let mut document = make_document(&[
+        "2019-05-04T12:00:00Z",
+        "2019-05-04T12:30:00Z",
+        "2019-05-04T15:00:00Z",
+        "2019-05-04T15:10:00Z",
+    ]);
+    // A point without a time goes with the previous ones.
+    document.tracks[0].segments[0]
+        .points
+        .push(Point::new(45.5, -73.5));
+    document.tracks[0].segments.push(Segment {
+        points: make_document(&["2019-05-04T15:20:00Z"]).tracks[0].segments[0]
+            .points
+            .clone(),
+    });
+
+    let splitter = Splitter {
+        mode: Mode::Trip,
+        trip_gap: DEFAULT_TRIP_GAP,
+        template: Template::new(
+            "{model}-{index}-{first_point_time:%Y-%m-%d-%H%M%S}.gpx",
+            Arc::new(|_| 3600),
+        ),
+    };
+    let parts = splitter.split(&document);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].document.track_points().count(), 2);
+    assert_eq!(parts[1].document.track_points().count(), 4);
+    assert_eq!(parts[1].document.tracks[0].segments.len(), 2);
+    assert_eq!(
+        splitter.file_name("m241", &parts[1], 2, now),
+        "m241-2-2019-05-04-160000.gpx"
+    );
+
+    let none = Splitter {
+        mode: Mode::None,
+        ..splitter
+    };
+    let parts = none.split(&document);
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].document, document);
+}
@@ -0,0 +1,189 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::track::{Document, Point, Time};
+
+/// Mean earth radius in meters.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+/// Speed under which we consider not moving, in m/s.
+const MOVING_SPEED: f64 = 0.5;
+
+/// The bounding box of the points, in degrees.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct Bounds {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+/// Statistics of the downloaded data.
+#[derive(Clone, Debug, Default, PartialEq, RustcEncodable)]
+pub struct Stats {
+    pub tracks: usize,
+    /// Number of track points.
+    pub points: usize,
+    pub waypoints: usize,
+    pub start_time: Option<Time>,
+    pub end_time: Option<Time>,
+    /// Total distance in meters.
+    pub distance: f64,
+    /// Time spent moving, in seconds.
+    pub moving_time: f64,
+    /// Speeds in m/s.
+    pub max_speed: f64,
+    pub avg_speed: f64,
+    /// Sum of the climbs, in meters.
+    pub elevation_gain: f64,
+    pub bounds: Option<Bounds>,
+}
+
+/// The distance between two points in meters, on a spherical earth.
+fn distance(p1: &Point, p2: &Point) -> f64 {
+    let (lat1, lat2) = (p1.lat.to_radians(), p2.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (p2.lon - p1.lon).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+impl Stats {
+    /// Compute the statistics for `document`.
+    pub fn compute(document: &Document) -> Stats {
+        let mut stats = Stats {
+            tracks: document.tracks.len(),
+            points: document.track_points().count(),
+            waypoints: document.waypoints.len(),
+            ..Stats::default()
+        };
+        if let Some((start, end)) = document.time_span() {
+            stats.start_time = Some(start);
+            stats.end_time = Some(end);
+        }
+
+        let mut recorded_max_speed = None;
+        let mut computed_max_speed: f64 = 0.0;
+        for segment in document.tracks.iter().flat_map(|track| &track.segments) {
+            for pair in segment.points.windows(2) {
+                let (p1, p2) = (&pair[0], &pair[1]);
+                let d = distance(p1, p2);
+                stats.distance += d;
+                if let (Some(t1), Some(t2)) = (p1.time, p2.time) {
+                    let dt = (t2.as_secs_f64() - t1.as_secs_f64()).abs();
+                    if dt > 0.0 {
+                        let speed = d / dt;
+                        if speed >= MOVING_SPEED {
+                            stats.moving_time += dt;
+                        }
+                        computed_max_speed = computed_max_speed.max(speed);
+                    }
+                }
+                if let (Some(e1), Some(e2)) = (p1.ele, p2.ele) {
+                    if e2 > e1 {
+                        stats.elevation_gain += e2 - e1;
+                    }
+                }
+            }
+            for speed in segment.points.iter().filter_map(|p| p.speed) {
+                recorded_max_speed = Some(recorded_max_speed.unwrap_or(0.0_f64).max(speed));
+            }
+        }
+        // The speed recorded by the logger is more reliable.
+        stats.max_speed = recorded_max_speed.unwrap_or(computed_max_speed);
+        if stats.moving_time > 0.0 {
+            stats.avg_speed = stats.distance / stats.moving_time;
+        }
+
+        for point in document.track_points().chain(document.waypoints.iter()) {
+            let bounds = stats.bounds.get_or_insert(Bounds {
+                min_lat: point.lat,
+                min_lon: point.lon,
+                max_lat: point.lat,
+                max_lon: point.lon,
+            });
+            bounds.min_lat = bounds.min_lat.min(point.lat);
+            bounds.min_lon = bounds.min_lon.min(point.lon);
+            bounds.max_lat = bounds.max_lat.max(point.lat);
+            bounds.max_lon = bounds.max_lon.max(point.lon);
+        }
+        stats
+    }
+
+    /// Whether there is nothing.
+    pub fn is_empty(&self) -> bool {
+        self.points == 0 && self.waypoints == 0
+    }
+}
+
+#[cfg(test)]
+fn make_point(lat: f64, lon: f64, ele: f64, time: &str) -> Point {
+    let mut point = Point::new(lat, lon);
+    point.ele = Some(ele);
+    point.time = Time::parse(time);
+    point
+}
+
+#[test]
+fn test_distance() {
+    let p1 = Point::new(0.0, 0.0);
+    let p2 = Point::new(0.0, 1.0);
+    // One degree at the equator is ~111.2 km
+    assert!((distance(&p1, &p2) - 111_195.0).abs() < 1.0);
+    assert_eq!(distance(&p1, &p1), 0.0);
+}
+
+#[test]
+fn test_stats() {
+    use crate::track::{Segment, Track};
+
+    let empty = Stats::compute(&Document::default());
+    assert!(empty.is_empty());
+    assert_eq!(empty.bounds, None);
+
+    let points = vec![
+        make_point(0.0, 0.0, 10.0, "2019-05-04T12:00:00Z"),
+        // ~1112m in 100s
+        make_point(0.0, 0.01, 15.0, "2019-05-04T12:01:40Z"),
+        // Not moving for 100s.
+        make_point(0.0, 0.01, 12.0, "2019-05-04T12:03:20Z"),
+        make_point(0.01, 0.01, 20.0, "2019-05-04T12:05:00Z"),
+    ];
+    let document = Document {
+        tracks: vec![Track {
+            name: None,
+            segments: vec![Segment { points }],
+        }],
+        waypoints: vec![Point::new(-1.0, 0.5)],
+    };
+    let stats = Stats::compute(&document);
+    assert!(!stats.is_empty());
+    assert_eq!(stats.tracks, 1);
+    assert_eq!(stats.points, 4);
+    assert_eq!(stats.waypoints, 1);
+    assert_eq!(stats.start_time, Time::parse("2019-05-04T12:00:00Z"));
+    assert_eq!(stats.end_time, Time::parse("2019-05-04T12:05:00Z"));
+    assert!((stats.distance - 2224.0).abs() < 1.0);
+    assert_eq!(stats.moving_time, 200.0);
+    assert!((stats.avg_speed - 11.12).abs() < 0.01);
+    assert!((stats.max_speed - 11.12).abs() < 0.01);
+    assert_eq!(stats.elevation_gain, 13.0);
+    assert_eq!(
+        stats.bounds,
+        Some(Bounds {
+            min_lat: -1.0,
+            min_lon: 0.0,
+            max_lat: 0.01,
+            max_lon: 0.5,
+        })
+    );
+}
@@ -0,0 +1,138 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counter to make the names in the destination directory unique.
+static PERSIST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A file in a private temporary directory. The directory is deleted
+/// with its content when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a new private temporary directory to hold a file named `name`.
+    /// The file itself isn't created.
+    pub fn new(name: &str) -> io::Result<TempFile> {
+        let template = env::temp_dir().join("gpsami-XXXXXX");
+        let template = CString::new(template.as_os_str().as_bytes())?;
+        let raw = template.into_raw();
+        // mkdtemp() creates the directory with mode 0700.
+        let result = unsafe { libc::mkdtemp(raw) };
+        let template = unsafe { CString::from_raw(raw) };
+        if result.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let dir = PathBuf::from(OsString::from_vec(template.into_bytes()));
+        Ok(TempFile {
+            path: dir.join(name),
+            dir,
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `dest`. `dest` is either replaced by the
+    /// complete file, or left untouched.
+    pub fn persist(self, dest: &Path) -> io::Result<()> {
+        persist(&self.path, dest)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            if err.kind() != io::ErrorKind::NotFound {
+                eprintln!("Can't remove temporary dir {:?}: {}", self.dir, err);
+            }
+        }
+    }
+}
+
+/// Atomically move `src` to `dest`.
+fn persist(src: &Path, dest: &Path) -> io::Result<()> {
+    if fs::rename(src, dest).is_ok() {
+        return Ok(());
+    }
+
+    // Likely not the same file system: copy to a temporary file next
+    // to the destination, and rename it.
+    let file_name = dest
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid destination"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        PERSIST_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let temp = dest.with_file_name(temp_name);
+    let result = fs::copy(src, &temp)
+        .and_then(|_| fs::File::open(&temp)?.sync_all())
+        .and_then(|_| fs::rename(&temp, dest));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+#[test]
+fn test_temp_file() {
+    let temp1 = TempFile::new("gpsami.gpx").unwrap();
+    let temp2 = TempFile::new("gpsami.gpx").unwrap();
+    assert_ne!(temp1.path(), temp2.path());
+    assert_eq!(temp1.path().file_name().unwrap(), "gpsami.gpx");
+    assert!(!temp1.path().exists());
+
+    fs::write(temp1.path(), "data").unwrap();
+    let dir = temp1.path().parent().unwrap().to_owned();
+    assert!(dir.is_dir());
+    drop(temp1);
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_persist() {
+    let dest_dir = TempFile::new("out").unwrap();
+    fs::create_dir(dest_dir.path()).unwrap();
+    let dest = dest_dir.path().join("out.gpx");
+    fs::write(&dest, "old").unwrap();
+
+    let temp = TempFile::new("gpsami.gpx").unwrap();
+    fs::write(temp.path(), "new").unwrap();
+    let dir = temp.path().parent().unwrap().to_owned();
+    temp.persist(&dest).unwrap();
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+    assert!(!dir.exists());
+
+    // Nothing to move: the destination is left untouched.
+    let temp = TempFile::new("gpsami.gpx").unwrap();
+    assert!(temp.persist(&dest).is_err());
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+    assert_eq!(fs::read_dir(dest_dir.path()).unwrap().count(), 1);
+}
@@ -0,0 +1,292 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Output file name templates.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::drivers::Error;
+use crate::gpsbabel::GpsBabel;
+use crate::gpx;
+use crate::tempfile::TempFile;
+use crate::track::{civil_from_days, days_from_civil, Time};
+use crate::Format;
+
+/// The default file name template.
+pub const DEFAULT_TEMPLATE: &str = "{model}-{date}.{ext}";
+/// The default format of the times.
+const DEFAULT_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// The offset from UTC of a timezone at a given time, in seconds.
+pub type Offset = Arc<dyn Fn(Time) -> i64 + Send + Sync>;
+
+/// The UTC offset.
+#[cfg(test)]
+pub fn utc() -> Offset {
+    Arc::new(|_| 0)
+}
+
+/// The offset of the timezone `id`, like `Europe/Paris`. None or an
+/// empty id is the local timezone.
+pub fn timezone(id: Option<&str>) -> Offset {
+    let timezone = match id {
+        Some(id) if !id.is_empty() => glib::TimeZone::new(Some(id)),
+        _ => glib::TimeZone::new_local(),
+    };
+    Arc::new(move |time| {
+        let interval = timezone.find_interval(glib::TimeType::Universal, time.unix());
+        i64::from(timezone.get_offset(interval))
+    })
+}
+
+/// The values to name a file.
+pub struct Values<'a> {
+    pub model: &'a str,
+    /// When it was downloaded.
+    pub date: Time,
+    pub first_point_time: Option<Time>,
+    pub last_point_time: Option<Time>,
+    /// The number of the file, when split.
+    pub index: Option<usize>,
+    /// The extension of the format, without the `.`.
+    pub extension: &'a str,
+}
+
+/// A file name template, like `{model}-{date:%Y%m%d}.gpx`.
+///
+/// `{model}` is the device model, `{date}` when it was downloaded,
+/// `{first_point_time}` and `{last_point_time}` the time of the first
+/// and last point, `{index}` the number of the file and `{ext}` the
+/// extension of the format. Times are
+/// local to the timezone, and the format after the `:` supports `%Y`,
+/// `%y`, `%m`, `%d`, `%j`, `%H`, `%M`, `%S` and `%%`.
+pub struct Template {
+    pattern: String,
+    offset: Offset,
+}
+
+/// Format `time` with `format`, `offset` seconds from UTC.
+fn format_time(time: Time, offset: i64, format: &str) -> String {
+    let secs = time.unix() + offset;
+    let days = secs.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs.rem_euclid(86400);
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => result.push_str(&format!("{:04}", year)),
+            Some('y') => result.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('m') => result.push_str(&format!("{:02}", month)),
+            Some('d') => result.push_str(&format!("{:02}", day)),
+            Some('j') => result.push_str(&format!("{:03}", days - days_from_civil(year, 1, 1) + 1)),
+            Some('H') => result.push_str(&format!("{:02}", secs / 3600)),
+            Some('M') => result.push_str(&format!("{:02}", (secs / 60) % 60)),
+            Some('S') => result.push_str(&format!("{:02}", secs % 60)),
+            Some('%') => result.push('%'),
+            // Unknown, keep as is.
+            Some(c) => {
+                result.push('%');
+                result.push(c);
+            }
+            None => result.push('%'),
+        }
+    }
+    result
+}
+
+impl Template {
+    pub fn new(pattern: &str, offset: Offset) -> Template {
+        Template {
+            pattern: pattern.to_string(),
+            offset,
+        }
+    }
+
+    /// The offset from UTC of the timezone at `time`, in seconds.
+    pub fn offset(&self, time: Time) -> i64 {
+        (self.offset)(time)
+    }
+
+    /// Expand the template with `values`. Unknown placeholders are
+    /// kept as is.
+    pub fn expand(&self, values: &Values) -> String {
+        let mut result = String::new();
+        let mut rest = self.pattern.as_str();
+        while let Some(start) = rest.find('{') {
+            result.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => {
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+            let placeholder = &rest[start + 1..end];
+            let (name, format) = match placeholder.find(':') {
+                Some(idx) => (&placeholder[..idx], Some(&placeholder[idx + 1..])),
+                None => (placeholder, None),
+            };
+            let time = |time: Option<Time>| match time {
+                Some(time) => format_time(
+                    time,
+                    (self.offset)(time),
+                    format.unwrap_or(DEFAULT_TIME_FORMAT),
+                ),
+                None => "undated".to_string(),
+            };
+            let value = match name {
+                "model" => Some(values.model.to_string()),
+                "date" => Some(time(Some(values.date))),
+                "first_point_time" => Some(time(values.first_point_time)),
+                "last_point_time" => Some(time(values.last_point_time)),
+                "index" => Some(values.index.unwrap_or(1).to_string()),
+                "ext" => Some(values.extension.to_string()),
+                _ => None,
+            };
+            match value {
+                // The values can't add directories.
+                Some(value) => result.push_str(&value.replace('/', "-")),
+                None => result.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// The current time.
+pub fn now() -> Time {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Time::from_unix(now.as_secs() as i64, now.subsec_millis())
+}
+
+/// The file extension of `format`, without the `.`.
+pub fn extension(format: Format) -> &'static str {
+    GpsBabel::format_to_extension(&format)
+        .map(|extension| extension.trim_start_matches('.'))
+        .unwrap_or_default()
+}
+
+/// A path for `name` in `dir` that doesn't exist yet, adding a number
+/// to the name if needed: `out.gpx`, `out-1.gpx`, `out-2.gpx`...
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let parent = path.parent().unwrap_or(dir).to_owned();
+    (1..)
+        .map(|n| parent.join(format!("{}-{}{}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Save the download `temp` into `dir`, named from `template`.
+/// Directories in the name are created. Return the file written.
+pub fn save_in(
+    temp: TempFile,
+    format: Format,
+    dir: &Path,
+    template: &Template,
+    model: &str,
+) -> Result<PathBuf, Error> {
+    // The point times are only known for GPX.
+    let time_span = if format == Format::Gpx {
+        fs::read_to_string(temp.path())
+            .ok()
+            .and_then(|content| gpx::read(&content).ok())
+            .and_then(|document| document.time_span())
+    } else {
+        None
+    };
+    let name = template.expand(&Values {
+        model,
+        date: now(),
+        first_point_time: time_span.map(|(start, _)| start),
+        last_point_time: time_span.map(|(_, end)| end),
+        index: None,
+        extension: extension(format),
+    });
+    let output = unique_path(dir, &name);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(Error::IOError)?;
+    }
+    temp.persist(&output).map_err(Error::IOError)?;
+    Ok(output)
+}
+
+#[test]
+fn test_expand() {
+    let values = Values {
+        model: "m241",
+        date: Time::parse("2019-05-04T12:34:56Z").unwrap(),
+        first_point_time: Time::parse("2019-02-01T23:30:00Z"),
+        last_point_time: None,
+        index: Some(3),
+        extension: "gpx",
+    };
+    let template = Template::new(DEFAULT_TEMPLATE, utc());
+    assert_eq!(template.expand(&values), "m241-20190504-123456.gpx");
+
+    let template = Template::new(
+        "{model}-{date:%Y%m%d}-{first_point_time}-{last_point_time}-{index}.gpx",
+        utc(),
+    );
+    assert_eq!(
+        template.expand(&values),
+        "m241-20190504-20190201-233000-undated-3.gpx"
+    );
+
+    // One hour ahead of UTC, the first point is the next day.
+    let template = Template::new(
+        "{first_point_time:%y/%j %H:%M:%S %%%q}_{bogus}_{model",
+        Arc::new(|_| 3600),
+    );
+    assert_eq!(
+        template.expand(&values),
+        "19-033 00:30:00 %%q_{bogus}_{model"
+    );
+}
+
+#[test]
+fn test_unique_path() {
+    let dir = TempFile::new("unique").unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir).unwrap();
+    assert_eq!(unique_path(dir, "out.gpx"), dir.join("out.gpx"));
+    fs::write(dir.join("out.gpx"), "").unwrap();
+    assert_eq!(unique_path(dir, "out.gpx"), dir.join("out-1.gpx"));
+    fs::write(dir.join("out-1.gpx"), "").unwrap();
+    assert_eq!(unique_path(dir, "out.gpx"), dir.join("out-2.gpx"));
+    fs::write(dir.join("README"), "").unwrap();
+    assert_eq!(unique_path(dir, "README"), dir.join("README-1"));
+}
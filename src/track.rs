@@ -0,0 +1,322 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+use rustc_serialize::{Encodable, Encoder};
+
+/// A UTC time, in milliseconds since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time(i64);
+
+/// Convert the number of days since the epoch to a (year, month, day).
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Convert a (year, month, day) to the number of days since the epoch.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = i64::from(month);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parse exactly `len` digits at the start of `s`.
+fn parse_digits(s: &str, len: usize) -> Option<u32> {
+    let digits = s.get(..len)?;
+    if !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+impl Time {
+    pub fn from_unix(secs: i64, millis: u32) -> Time {
+        Time(secs * 1000 + i64::from(millis))
+    }
+
+    /// The time in seconds since the epoch.
+    pub fn unix(self) -> i64 {
+        self.0.div_euclid(1000)
+    }
+
+    /// The time in seconds since the epoch, with the milliseconds.
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// Parse an ISO 8601 time like `2019-05-04T12:00:00.250Z`. Times
+    /// without a timezone are assumed to be UTC.
+    pub fn parse(s: &str) -> Option<Time> {
+        let s = s.trim();
+        let year = parse_digits(s, 4)?;
+        let month = parse_digits(s.get(5..)?, 2)?;
+        let day = parse_digits(s.get(8..)?, 2)?;
+        let hour = parse_digits(s.get(11..)?, 2)?;
+        let minute = parse_digits(s.get(14..)?, 2)?;
+        let second = parse_digits(s.get(17..)?, 2)?;
+        if s.get(4..5)? != "-"
+            || s.get(7..8)? != "-"
+            || !matches!(s.get(10..11)?, "T" | "t" | " ")
+            || s.get(13..14)? != ":"
+            || s.get(16..17)? != ":"
+            || !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || hour > 23
+            || minute > 59
+            // Allow leap seconds.
+            || second > 60
+        {
+            return None;
+        }
+
+        let mut rest = &s[19..];
+        let mut millis = 0;
+        if rest.starts_with('.') {
+            let count = rest[1..].bytes().take_while(u8::is_ascii_digit).count();
+            if count == 0 {
+                return None;
+            }
+            // Only keep the milliseconds.
+            let fraction = &rest[1..=count.min(3)];
+            millis = fraction.parse::<u32>().ok()? * 10_u32.pow(3 - fraction.len() as u32);
+            rest = &rest[1 + count..];
+        }
+        let offset = match rest {
+            "" | "Z" | "z" => 0,
+            _ => {
+                let sign = match rest.get(..1)? {
+                    "+" => 1,
+                    "-" => -1,
+                    _ => return None,
+                };
+                let hours = parse_digits(&rest[1..], 2)?;
+                let minutes = match rest.get(3..)? {
+                    "" => 0,
+                    m if m.starts_with(':') && m.len() == 3 => parse_digits(&m[1..], 2)?,
+                    m if m.len() == 2 => parse_digits(m, 2)?,
+                    _ => return None,
+                };
+                sign * i64::from(hours * 3600 + minutes * 60)
+            }
+        };
+
+        let days = days_from_civil(i64::from(year), month, day);
+        let secs =
+            days * 86400 + i64::from(hour) * 3600 + i64::from(minute) * 60 + i64::from(second)
+                - offset;
+        Some(Time::from_unix(secs, millis))
+    }
+}
+
+impl fmt::Display for Time {
+    /// Format the UTC time as ISO 8601, as expected by GPX.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.unix();
+        let millis = self.0.rem_euclid(1000);
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let secs = secs.rem_euclid(86400);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60
+        )?;
+        if millis != 0 {
+            write!(f, ".{:03}", millis)?;
+        }
+        write!(f, "Z")
+    }
+}
+
+impl Encodable for Time {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+/// A point, in a track or a waypoint.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Point {
+    /// Latitude and longitude in degrees, WGS84.
+    pub lat: f64,
+    pub lon: f64,
+    /// Elevation in meters.
+    pub ele: Option<f64>,
+    pub time: Option<Time>,
+    /// Course in degrees.
+    pub course: Option<f64>,
+    /// Speed in m/s.
+    pub speed: Option<f64>,
+    /// Number of satellites.
+    pub sat: Option<u32>,
+    pub hdop: Option<f64>,
+    pub vdop: Option<f64>,
+    pub pdop: Option<f64>,
+    pub name: Option<String>,
+}
+
+impl Point {
+    pub fn new(lat: f64, lon: f64) -> Point {
+        Point {
+            lat,
+            lon,
+            ..Point::default()
+        }
+    }
+}
+
+/// A continuous segment of a track.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Segment {
+    pub points: Vec<Point>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Track {
+    pub name: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+impl Track {
+    /// All the points of the track.
+    pub fn points(&self) -> impl Iterator<Item = &Point> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.points.iter())
+    }
+}
+
+/// The data downloaded from a logger.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Document {
+    pub tracks: Vec<Track>,
+    pub waypoints: Vec<Point>,
+}
+
+impl Document {
+    /// All the track points.
+    pub fn track_points(&self) -> impl Iterator<Item = &Point> {
+        self.tracks.iter().flat_map(|track| track.points())
+    }
+
+    /// The number of track points and waypoints.
+    pub fn point_count(&self) -> usize {
+        self.track_points().count() + self.waypoints.len()
+    }
+
+    /// The time of the first and the last point.
+    pub fn time_span(&self) -> Option<(Time, Time)> {
+        let mut times = self
+            .track_points()
+            .chain(self.waypoints.iter())
+            .filter_map(|point| point.time);
+        let first = times.next()?;
+        Some(times.fold((first, first), |(start, end), time| {
+            (start.min(time), end.max(time))
+        }))
+    }
+}
+
+#[test]
+fn test_time_format() {
+    assert_eq!(Time::from_unix(0, 0).to_string(), "1970-01-01T00:00:00Z");
+    assert_eq!(
+        Time::from_unix(1_582_416_000, 0).to_string(),
+        "2020-02-23T00:00:00Z"
+    );
+    assert_eq!(
+        Time::from_unix(951_825_599, 250).to_string(),
+        "2000-02-29T11:59:59.250Z"
+    );
+    assert_eq!(Time::from_unix(-1, 0).to_string(), "1969-12-31T23:59:59Z");
+}
+
+#[test]
+fn test_time_parse() {
+    assert_eq!(
+        Time::parse("2000-02-29T11:59:59.250Z"),
+        Some(Time::from_unix(951_825_599, 250))
+    );
+    assert_eq!(
+        Time::parse("2020-02-23T00:00:00Z"),
+        Some(Time::from_unix(1_582_416_000, 0))
+    );
+    assert_eq!(
+        Time::parse("2020-02-23T00:00:00"),
+        Some(Time::from_unix(1_582_416_000, 0))
+    );
+    assert_eq!(
+        Time::parse("2020-02-22T19:00:00.5-05:00"),
+        Some(Time::from_unix(1_582_416_000, 500))
+    );
+    assert_eq!(
+        Time::parse("2020-02-23T01:30:00.123456+0130"),
+        Some(Time::from_unix(1_582_416_000, 123))
+    );
+    assert_eq!(Time::parse("2020-02-23"), None);
+    assert_eq!(Time::parse("2020-13-23T00:00:00Z"), None);
+    assert_eq!(Time::parse("2020-02-23T00:00:00.Z"), None);
+    assert_eq!(Time::parse("2020-02-23T00:00:00Y"), None);
+
+    for days in &[-719_468, -1, 0, 11_016, 18_315, 2_932_896] {
+        let (year, month, day) = civil_from_days(*days);
+        assert_eq!(days_from_civil(year, month, day), *days);
+    }
+}
+
+#[test]
+fn test_document() {
+    let mut point1 = Point::new(45.5, -73.5);
+    point1.time = Time::parse("2019-05-04T13:00:00Z");
+    let mut point2 = Point::new(45.6, -73.6);
+    point2.time = Time::parse("2019-05-04T12:00:00Z");
+    let mut waypoint = Point::new(45.6, -73.6);
+    waypoint.time = Time::parse("2019-05-04T14:00:00Z");
+    let mut document = Document::default();
+    assert_eq!(document.time_span(), None);
+
+    document.tracks.push(Track {
+        name: None,
+        segments: vec![Segment {
+            points: vec![point1, point2, Point::new(0.0, 0.0)],
+        }],
+    });
+    document.waypoints.push(waypoint);
+    assert_eq!(document.point_count(), 4);
+    assert_eq!(
+        document.time_span(),
+        Some((
+            Time::parse("2019-05-04T12:00:00Z").unwrap(),
+            Time::parse("2019-05-04T14:00:00Z").unwrap()
+        ))
+    );
+}
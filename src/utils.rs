@@ -29,3 +29,14 @@ pub fn setup_text_combo(combo: &gtk::ComboBox, model: &gtk::ListStore) {
 pub fn add_text_row(store: &gtk::ListStore, col1: &str, col2: &str) -> gtk::TreeIter {
     store.insert_with_values(None, &[0, 1], &[&String::from(col1), &String::from(col2)])
 }
+
+/// Add a text column to the tree view, showing `column` of the model.
+pub fn add_text_column(view: &gtk::TreeView, title: &str, column: i32) {
+    let cell = gtk::CellRendererText::new();
+    let view_column = gtk::TreeViewColumn::new();
+    view_column.set_title(title);
+    view_column.set_resizable(true);
+    view_column.pack_start(&cell, true);
+    view_column.add_attribute(&cell, "text", column);
+    view.append_column(&view_column);
+}
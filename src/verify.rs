@@ -0,0 +1,196 @@
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verify the downloaded data before erasing the device.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::devices::Capability;
+use crate::drivers::{CancelToken, Driver, Error, ProgressFn};
+use crate::gpx;
+use crate::Format;
+
+/// When the device is erased, downloading.
+#[derive(Debug, PartialEq)]
+pub enum Erase {
+    No,
+    /// By the driver while downloading, without any check.
+    WhileDownloading,
+    /// Once the files are saved, checking them if `check`.
+    AfterSaving {
+        check: bool,
+    },
+}
+
+/// When to erase the device with `capability`, if `erase`. The data is
+/// checked if `verify`, unless the device can only be erased while
+/// downloading. It then can't be `converted` by gpsbabel afterwards,
+/// as the data would be lost if that failed.
+pub fn erase_mode(
+    capability: &Capability,
+    erase: bool,
+    verify: bool,
+    converted: bool,
+) -> Result<Erase, Error> {
+    if !erase {
+        Ok(Erase::No)
+    } else if capability.can_erase_only {
+        Ok(Erase::AfterSaving { check: verify })
+    } else if converted {
+        Err(Error::Failed(
+            "This device can only be erased while downloading GPX without filters.".to_string(),
+        ))
+    } else {
+        Ok(Erase::WhileDownloading)
+    }
+}
+
+/// Check that the data saved, the files of each format, is complete:
+/// no file empty or invalid, and with track points. Only GPX can be
+/// checked beyond being empty. The drivers check that they read all
+/// the device logged.
+pub fn check_download(saved: &[(Format, PathBuf)]) -> Result<(), Error> {
+    // The track points in GPX, if any.
+    let mut points = None;
+    for (format, path) in saved {
+        let content = fs::read(path).map_err(Error::IOError)?;
+        if content.is_empty() {
+            return Err(Error::Failed("The downloaded file is empty.".to_string()));
+        }
+        if *format != Format::Gpx {
+            continue;
+        }
+        let document = gpx::read(&String::from_utf8_lossy(&content))
+            .map_err(|err| Error::Failed(format!("The downloaded file is invalid: {}", err)))?;
+        *points.get_or_insert(0) += document.track_points().count() as u64;
+    }
+    match points {
+        Some(0) => Err(Error::Failed("No points were downloaded.".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Erase `device` once the data it was downloaded into `saved` is checked.
+pub fn erase_after_check(
+    device: &dyn Driver,
+    saved: &[(Format, PathBuf)],
+    progress: &ProgressFn,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    check_download(saved)
+        .map_err(|err| Error::Failed(format!("{} The device wasn't erased.", err)))?;
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    match device.erase(progress, cancel) {
+        Err(Error::Unsupported) => Err(Error::Failed(
+            "This device can only be erased while downloading. \
+             Turn off the verification to erase it."
+                .to_string(),
+        )),
+        result => result,
+    }
+}
+
+/// Erase `device` once the data was downloaded into `saved`, after
+/// checking it if `check`.
+pub fn erase_after_saving(
+    device: &dyn Driver,
+    saved: &[(Format, PathBuf)],
+    check: bool,
+    progress: &ProgressFn,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    if check {
+        erase_after_check(device, saved, progress, cancel)
+    } else if cancel.is_cancelled() {
+        Err(Error::Cancelled)
+    } else {
+        device.erase(progress, cancel)
+    }
+}
+
+#[test]
+fn test_erase_mode() {
+    let mut capability: Capability = rustc_serialize::json::decode(
+        r#"{ "can_erase": true, "can_erase_only": true,
+             "can_log_enable": false, "can_shutoff": false }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        erase_mode(&capability, false, true, true).unwrap(),
+        Erase::No
+    );
+    assert_eq!(
+        erase_mode(&capability, true, true, false).unwrap(),
+        Erase::AfterSaving { check: true }
+    );
+    assert_eq!(
+        erase_mode(&capability, true, false, true).unwrap(),
+        Erase::AfterSaving { check: false }
+    );
+    // Can't be erased separately: no check, and no conversion.
+    capability.can_erase_only = false;
+    assert_eq!(
+        erase_mode(&capability, true, true, false).unwrap(),
+        Erase::WhileDownloading
+    );
+    assert!(erase_mode(&capability, true, true, true).is_err());
+    assert_eq!(
+        erase_mode(&capability, false, true, true).unwrap(),
+        Erase::No
+    );
+}
+
+#[test]
+fn test_check_download() {
+    use crate::tempfile::TempFile;
+
+    let temp = TempFile::new("out.gpx").unwrap();
+    let path = temp.path();
+    let paths = [(Format::Gpx, path.to_owned())];
+    assert!(check_download(&paths).is_err());
+
+    fs::write(path, "").unwrap();
+    assert!(check_download(&paths).is_err());
+    fs::write(path, "<gpx><trk><trkseg>").unwrap();
+    assert!(check_download(&paths).is_err());
+    // Non GPX is only checked for emptiness.
+    assert!(check_download(&[(Format::Kml, path.to_owned())]).is_ok());
+    fs::write(path, "<gpx></gpx>").unwrap();
+    assert!(check_download(&paths).is_err());
+
+    fs::write(
+        path,
+        r#"<gpx><trk><trkseg>
+<trkpt lat="45.5" lon="-73.5"/><trkpt lat="45.6" lon="-73.5"/>
+</trkseg></trk></gpx>"#,
+    )
+    .unwrap();
+    assert!(check_download(&paths).is_ok());
+    // The points of all the files are counted.
+    let other = TempFile::new("other.gpx").unwrap();
+    fs::write(other.path(), "<gpx></gpx>").unwrap();
+    let both = [
+        (Format::Gpx, path.to_owned()),
+        (Format::Gpx, other.path().to_owned()),
+    ];
+    assert!(check_download(&both).is_ok());
+    assert_eq!(
+        check_download(&[(Format::Gpx, other.path().to_owned())])
+            .unwrap_err()
+            .to_string(),
+        "No points were downloaded."
+    );
+}